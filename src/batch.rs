@@ -0,0 +1,162 @@
+use std::io::BufRead;
+
+use anyhow::Context;
+
+/// Reads one command line per input line, skipping blank lines and `#` comments
+pub fn read_commands(input: impl BufRead) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut commands = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line.context("failed to read batch input")?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words =
+            split_command_line(line).with_context(|| format!("batch line {}", number + 1))?;
+        commands.push(words);
+    }
+    Ok(commands)
+}
+
+/// Splits a command line into words the way a POSIX shell does for quoting: single quotes
+/// keep everything literally, double quotes allow `\"` and `\\` escapes and a backslash
+/// outside quotes escapes the next character. No expansions are performed.
+pub(crate) fn split_command_line(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => anyhow::bail!("unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => anyhow::bail!("unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => anyhow::bail!("unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some(c) => word.push(c),
+                    None => anyhow::bail!("trailing backslash"),
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Runs the commands one after another with `run`, which gets the command and its
+/// arguments. A failing command doesn't stop the batch, a command whose result `stops` the
+/// batch is the last one run.
+///
+/// # Returns
+/// The results of the commands which were run, in order
+pub fn run_commands<T>(
+    commands: &[Vec<String>],
+    mut run: impl FnMut(&str, &[String]) -> anyhow::Result<T>,
+    stops: impl Fn(&T) -> bool,
+) -> Vec<anyhow::Result<T>> {
+    let mut results = Vec::new();
+    for words in commands {
+        let result = run(&words[0], &words[1..]);
+        let stop = result.as_ref().is_ok_and(&stops);
+        results.push(result);
+        if stop {
+            break;
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_command_line() {
+        assert_eq!(
+            split_command_line(r#"sh -c 'echo "a b"; exit 3'"#).unwrap(),
+            vec!["sh", "-c", r#"echo "a b"; exit 3"#]
+        );
+        assert_eq!(
+            split_command_line(r#"  echo "x \"y\"" a\ b '' "#).unwrap(),
+            vec!["echo", r#"x "y""#, "a b", ""]
+        );
+        assert!(split_command_line("echo 'open").is_err());
+        assert!(split_command_line("echo \"open").is_err());
+    }
+
+    #[test]
+    fn test_batch_runs_every_command() {
+        let input = "# comment\n/bin/sh -c 'exit 0'\n\n/bin/sh -c \"exit 3\"\n";
+        let commands = read_commands(input.as_bytes()).unwrap();
+        assert_eq!(commands.len(), 2);
+
+        let results = run_commands(
+            &commands,
+            |command, args| {
+                let status = std::process::Command::new(command).args(args).status()?;
+                Ok(status.code().unwrap())
+            },
+            |_| false,
+        );
+        let codes: Vec<i32> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(codes, vec![0, 3]);
+    }
+
+    #[test]
+    fn test_batch_stops() {
+        let commands = read_commands("a 1\nb 2\nc 3\n".as_bytes()).unwrap();
+        let mut run = Vec::new();
+        let results = run_commands(
+            &commands,
+            |command, _| {
+                run.push(command.to_string());
+                match command {
+                    "a" => anyhow::bail!("failed to start"),
+                    _ => Ok(command.to_string()),
+                }
+            },
+            |command| command == "b",
+        );
+        assert_eq!(run, vec!["a", "b"]);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+    }
+}
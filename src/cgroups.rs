@@ -1,68 +1,160 @@
 use anyhow::{Context, Result};
+use nix::{
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-const CGROUP_PATH: &str = "/sys/fs/cgroup/";
+use crate::limiter::Limiter;
+
+/// Default mount point of the cgroup v2 hierarchy
+pub const CGROUP_PATH: &str = "/sys/fs/cgroup/";
+
+/// Host memory statistics, used to check memory limits against the host's RAM
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// How long to wait for killed processes to leave the cgroup before removing it
+const EMPTY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the processes in a cgroup to be frozen or thawed
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default CPU period used when only a quota is given, in microseconds
+const DEFAULT_CPU_PERIOD: u64 = 100000;
+
+/// Unit suffixes accepted by memory limits, case-insensitive
+const MEMORY_UNITS: [&str; 6] = ["K", "M", "G", "Kb", "Mb", "Gb"];
+
+/// Valid `cpu.max` periods in microseconds
+const CPU_PERIOD_RANGE: std::ops::RangeInclusive<u64> = 1000..=1_000_000;
+
+/// Smallest `cpu.max` quota in microseconds accepted by the kernel
+const MIN_CPU_QUOTA: u64 = 1000;
+
+/// Valid values for `memory.swappiness`, like the `vm.swappiness` sysctl
+pub const SWAPPINESS_RANGE: std::ops::RangeInclusive<i64> = 0..=200;
+
+/// Valid nice values for the CPU weight
+const NICE_RANGE: std::ops::RangeInclusive<i32> = -20..=19;
+
+/// Scheduler load weights for nice levels -20..19, `sched_prio_to_weight` in the kernel
+const NICE_TO_LOAD_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Resource limits applied to the container's cgroup
+#[derive(Debug, Default, Clone)]
+pub struct Limits {
+    /// CPU quota as decimal (e.g., "0.5" for 50% of one core)
+    pub cpu: Option<String>,
+    /// Memory limit (e.g., "100M", "1G")
+    pub mem: Option<String>,
+    /// Best-effort memory protection written to `memory.low`, same format as `mem`
+    pub mem_reservation: Option<String>,
+    /// Raw `cpu.max` quota in microseconds, alternative to `cpu`
+    pub cpu_quota: Option<u64>,
+    /// Raw `cpu.max` period in microseconds, used with `cpu_quota`
+    pub cpu_period: Option<u64>,
+    /// CPU weight as a nice value (-20..19), written to `cpu.weight`
+    pub cpu_nice: Option<i32>,
+    /// NUMA nodes the container may allocate memory from (e.g., "0", "0-1,3")
+    pub cpuset_mems: Option<String>,
+    /// Most processes and threads the container may have at once, written to `pids.max`
+    pub pids: Option<u64>,
+    /// How eagerly the container's memory is swapped out, 0..200, `memory.swappiness`. Only
+    /// hierarchies which provide the file support it, cgroup v2 doesn't
+    pub swappiness: Option<u8>,
+    /// Most cgroups the container may create below its own, `cgroup.max.descendants`
+    pub max_descendants: Option<u32>,
+    /// Deepest nesting of cgroups the container may create, `cgroup.max.depth`
+    pub max_depth: Option<u32>,
+}
 
 /// Represents a cgroup that can limit CPU and memory resources.
 /// Uses RAII pattern - automatically cleaned up when dropped.
 pub struct Cgroup {
+    root: PathBuf,
     path: PathBuf,
     cgroup: String,
+    /// Leave the cgroup directories in place on drop, for inspection after the run
+    keep: bool,
 }
 
 impl Cgroup {
-    /// Creates a new cgroup with the specified resource limits.
+    /// Lists the controllers supported by the cgroup v2 hierarchy mounted at `root`, as
+    /// found in `cgroup.controllers` (e.g., ["cpuset", "cpu", "io", "memory", "pids"]).
+    pub fn available_controllers(root: &Path) -> Result<Vec<String>> {
+        let controllers_file = root.join("cgroup.controllers");
+        let content = fs::read_to_string(&controllers_file).with_context(|| {
+            format!(
+                "Failed to read {:?}, is it a cgroup v2 hierarchy?",
+                controllers_file
+            )
+        })?;
+        Ok(content.split_whitespace().map(String::from).collect())
+    }
+
+    /// Creates a new cgroup able to apply the specified resource limits, which are set with
+    /// [Limiter::apply].
     ///
     /// # Arguments
-    /// * `memory` - Optional memory limit (e.g., "100M", "1G")
-    /// * `cpu` - Optional CPU quota as decimal (e.g., "0.5" for 50% of one core)
+    /// * `root` - Mount point of the cgroup v2 hierarchy, usually [CGROUP_PATH]
+    /// * `parent` - Cgroup relative to `root` to create the container's cgroup in, created
+    ///   if missing and kept on drop
+    /// * `limits` - Resource limits the cgroup needs controllers for
     ///
     /// # Returns
     /// A new Cgroup instance that will be automatically cleaned up on drop
-    pub fn new(cpu: &Option<String>, memory: &Option<String>) -> Result<Self> {
-        let cgroup_path = Path::new(CGROUP_PATH).join("toy_container");
+    pub fn new(root: &Path, parent: Option<&Path>, limits: &Limits) -> Result<Self> {
+        if limits.cpu.is_some() && (limits.cpu_quota.is_some() || limits.cpu_period.is_some()) {
+            anyhow::bail!("CPU limit and raw CPU quota/period are mutually exclusive");
+        }
+
+        // fail before creating anything if the host can't apply the limits
+        let required = required_controllers(limits);
+        if !required.is_empty() {
+            let available = Cgroup::available_controllers(root)?;
+            for controller in required {
+                if !available.iter().any(|c| c == controller) {
+                    anyhow::bail!(
+                        "cgroup controller {} is not available, the host supports: {}",
+                        controller,
+                        available.join(" ")
+                    );
+                }
+            }
+        }
+
+        let cgroup_path = match parent {
+            Some(parent) => root.join(parent).join("toy_container"),
+            None => root.join("toy_container"),
+        };
         println!("Setting up cgroup {:?}", cgroup_path);
 
         let cgroup = Cgroup {
+            root: root.to_path_buf(),
             path: cgroup_path,
             cgroup: String::from("leaf"),
+            keep: false,
         };
 
         // Ensure base cgroup directory exists and controllers are enabled
         cgroup
-            .ensure_base_cgroup(memory.is_some(), cpu.is_some())
+            .ensure_base_cgroup(&controller_string(limits))
             .context("Failed to setup base cgroup")?;
 
-        // Apply memory limit if specified
-        if let Some(mem_limit) = memory {
-            // Validate memory limit string before applying
-            validate_memory_limit(mem_limit)?;
-
-            cgroup
-                .set_memory_limit(mem_limit)
-                .with_context(|| format!("Failed to set memory limit to {}", mem_limit))?;
-        }
-
-        // Apply CPU limit if specified
-        if let Some(cpu_quota) = cpu {
-            cgroup
-                .set_cpu_limit(cpu_quota)
-                .with_context(|| format!("Failed to set CPU limit to {}", cpu_quota))?;
-        }
-
         Ok(cgroup)
     }
 
-    /// Adds a process to this cgroup.
-    ///
-    /// # Arguments
-    /// * `pid` - Process ID to add to the cgroup
-    pub fn add_process(&self, pid: i32) -> Result<()> {
-        let procs_file = self.path.join(&self.cgroup).join("cgroup.procs");
-        fs::write(&procs_file, pid.to_string())
-            .with_context(|| format!("Failed to add process {} to cgroup", pid))?;
-        Ok(())
+    /// Keeps the cgroup directories when the cgroup is dropped, so its statistics such as
+    /// `memory.events` can be inspected after the run. Its processes are still killed.
+    pub fn keep_after_drop(&mut self) {
+        self.keep = true;
     }
 
     /// Sets the memory limit for a cgroup.
@@ -77,13 +169,62 @@ impl Cgroup {
         Ok(())
     }
 
+    /// Sets the best-effort memory protection of the cgroup in `memory.low`.
+    ///
+    /// # Arguments
+    /// * `limit` - Memory amount in the `memory.max` format (e.g., "100M", "max"),
+    ///   written as bytes
+    pub fn set_memory_low(&self, limit: &str) -> Result<()> {
+        let value = match parse_memory_limit(limit)? {
+            Some(bytes) => bytes.to_string(),
+            None => String::from("max"),
+        };
+        let memory_low = self.path.join(&self.cgroup).join("memory.low");
+        fs::write(&memory_low, value)
+            .with_context(|| format!("Failed to write to {:?}", memory_low))?;
+        Ok(())
+    }
+
+    /// Caps the number of processes and threads in the cgroup in `pids.max`, so a fork bomb
+    /// can't exhaust the host's PIDs.
+    ///
+    /// # Arguments
+    /// * `limit` - Most tasks the cgroup may hold at once, greater than 0
+    pub fn set_pids_limit(&self, limit: u64) -> Result<()> {
+        let pids_max = self.path.join(&self.cgroup).join("pids.max");
+        fs::write(&pids_max, limit.to_string())
+            .with_context(|| format!("Failed to write to {:?}", pids_max))?;
+        Ok(())
+    }
+
+    /// Sets how eagerly the kernel swaps out the cgroup's memory in `memory.swappiness`.
+    /// cgroup v2 has no such file, there the host's `vm.swappiness` applies to every cgroup.
+    ///
+    /// # Arguments
+    /// * `swappiness` - Value in [SWAPPINESS_RANGE], 0 avoids swapping as long as possible
+    pub fn set_memory_swappiness(&self, swappiness: u8) -> Result<()> {
+        let memory_swappiness = self.path.join(&self.cgroup).join("memory.swappiness");
+        if !memory_swappiness.exists() {
+            anyhow::bail!(
+                "The cgroup has no memory.swappiness, cgroup v2 doesn't support per-cgroup \
+                 swappiness. Swap usage can be capped with memory.swap.max instead"
+            );
+        }
+        fs::write(&memory_swappiness, swappiness.to_string())
+            .with_context(|| format!("Failed to write to {:?}", memory_swappiness))?;
+        Ok(())
+    }
+
     /// Sets the CPU limit for a cgroup.
     ///
     /// # Arguments
     /// * `path` - Path to the cgroup directory
-    /// * `quota` - CPU quota as a decimal string (e.g., "0.5" for 50%)
+    /// * `quota` - CPU quota as a decimal string or a percentage (e.g., "0.5" or "50%")
     pub fn set_cpu_limit(&self, quota: &str) -> Result<()> {
-        let cpu_quota_str = parse_cpu_quota(quota)
+        let cores = std::thread::available_parallelism()
+            .context("Failed to get the number of CPUs")?
+            .get();
+        let cpu_quota_str = parse_cpu_quota(quota, cores)
             .with_context(|| format!("Failed to parse CPU quota '{}'", quota))?;
 
         let cpu_max = self.path.join(&self.cgroup).join("cpu.max");
@@ -92,8 +233,222 @@ impl Cgroup {
         Ok(())
     }
 
+    /// Writes raw quota and period values to `cpu.max`.
+    ///
+    /// # Arguments
+    /// * `quota` - CPU time in microseconds the cgroup may use per period
+    /// * `period` - Length of the period in microseconds
+    pub fn set_cpu_max(&self, quota: u64, period: u64) -> Result<()> {
+        let cpu_max_str = format_cpu_max(quota, period)?;
+
+        let cpu_max = self.path.join(&self.cgroup).join("cpu.max");
+        fs::write(&cpu_max, cpu_max_str)
+            .with_context(|| format!("Failed to write to {:?}", cpu_max))?;
+        Ok(())
+    }
+
+    /// Writes the relative CPU weight to `cpu.weight`.
+    ///
+    /// # Arguments
+    /// * `weight` - Weight between 1 and 10000, 100 is the default
+    pub fn set_cpu_weight(&self, weight: u32) -> Result<()> {
+        let cpu_weight = self.path.join(&self.cgroup).join("cpu.weight");
+        fs::write(&cpu_weight, weight.to_string())
+            .with_context(|| format!("Failed to write to {:?}", cpu_weight))?;
+        Ok(())
+    }
+
+    /// Restricts the NUMA nodes the cgroup may allocate memory from.
+    ///
+    /// # Arguments
+    /// * `mems` - NUMA node list (e.g., "0", "0-1,3")
+    pub fn set_cpuset_mems(&self, mems: &str) -> Result<()> {
+        let cpuset_mems = self.path.join(&self.cgroup).join("cpuset.mems");
+        fs::write(&cpuset_mems, mems)
+            .with_context(|| format!("Failed to write to {:?}", cpuset_mems))?;
+        Ok(())
+    }
+
+    /// Limits the sub-cgroups the container can create in its cgroup, e.g. through its own
+    /// cgroup namespace. Limits which are not set are left at "max".
+    ///
+    /// # Arguments
+    /// * `max_descendants` - Most cgroups below the container's, `cgroup.max.descendants`
+    /// * `max_depth` - Deepest nesting below the container's cgroup, `cgroup.max.depth`
+    pub fn set_cgroup_limits(
+        &self,
+        max_descendants: Option<u32>,
+        max_depth: Option<u32>,
+    ) -> Result<()> {
+        let leaf = self.path.join(&self.cgroup);
+        for (file, limit) in [
+            ("cgroup.max.descendants", max_descendants),
+            ("cgroup.max.depth", max_depth),
+        ] {
+            if let Some(limit) = limit {
+                let path = leaf.join(file);
+                fs::write(&path, limit.to_string())
+                    .with_context(|| format!("Failed to write to {:?}", path))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the processes in the cgroup from `cgroup.procs`.
+    pub fn procs(&self) -> Result<Vec<Pid>> {
+        let procs_file = self.path.join(&self.cgroup).join("cgroup.procs");
+        let content = fs::read_to_string(&procs_file)
+            .with_context(|| format!("Failed to read {:?}", procs_file))?;
+        content
+            .lines()
+            .map(|line| {
+                line.trim()
+                    .parse()
+                    .map(Pid::from_raw)
+                    .with_context(|| format!("Invalid pid '{}' in {:?}", line, procs_file))
+            })
+            .collect()
+    }
+
+    /// Freezes all processes in the cgroup by writing `1` to `cgroup.freeze` and waits until
+    /// they are all stopped. Does nothing if the cgroup is already frozen.
+    pub fn freeze(&self) -> Result<()> {
+        self.set_frozen(true)
+    }
+
+    /// Resumes all processes in the cgroup by writing `0` to `cgroup.freeze` and waits until
+    /// the cgroup is no longer frozen. Does nothing if the cgroup is not frozen.
+    pub fn thaw(&self) -> Result<()> {
+        self.set_frozen(false)
+    }
+
+    /// Checks whether the cgroup is frozen, as reported by the `frozen` field of
+    /// `cgroup.events`. Unlike `cgroup.freeze`, it is only set once every process is stopped.
+    pub fn is_frozen(&self) -> Result<bool> {
+        let events = self.path.join(&self.cgroup).join("cgroup.events");
+        let content =
+            fs::read_to_string(&events).with_context(|| format!("Failed to read {:?}", events))?;
+        parse_frozen(&content)
+    }
+
+    /// Requests the freezer state and polls `cgroup.events` until the transition is done,
+    /// writing to `cgroup.freeze` returns before the processes are actually stopped.
+    fn set_frozen(&self, frozen: bool) -> Result<()> {
+        if self.is_frozen()? == frozen {
+            return Ok(());
+        }
+        let freeze = self.path.join(&self.cgroup).join("cgroup.freeze");
+        fs::write(&freeze, if frozen { "1" } else { "0" })
+            .with_context(|| format!("Failed to write to {:?}", freeze))?;
+
+        let start = Instant::now();
+        while self.is_frozen()? != frozen {
+            if start.elapsed() >= FREEZE_TIMEOUT {
+                anyhow::bail!(
+                    "Cgroup did not {} within {:?}",
+                    if frozen { "freeze" } else { "thaw" },
+                    FREEZE_TIMEOUT
+                );
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Kills all processes in the cgroup at once by writing `1` to `cgroup.kill`.
+    pub fn kill_all(&self) -> Result<()> {
+        let kill = self.path.join(&self.cgroup).join("cgroup.kill");
+        fs::write(&kill, "1").with_context(|| format!("Failed to write to {:?}", kill))?;
+        Ok(())
+    }
+
+    /// Kills every process in the cgroup and waits for them to exit.
+    /// cgroup.kill is only available since Linux 5.14, falls back to killing one by one.
+    pub fn kill_remaining(&self) {
+        if self.kill_all().is_err()
+            && let Ok(procs) = self.procs()
+        {
+            for pid in procs {
+                let _ = kill(pid, Signal::SIGKILL);
+            }
+        }
+        self.wait_until_empty(EMPTY_TIMEOUT);
+    }
+
+    /// Delivers a signal to every process in the cgroup. The cgroup is frozen while
+    /// signalling, so no process can fork and escape the signal, and the processes
+    /// handle it only after they are thawed. The cgroup is thawed even if something failed,
+    /// a cgroup left frozen would never handle the signal.
+    ///
+    /// # Arguments
+    /// * `signal` - Signal to deliver
+    pub fn signal_all(&self, signal: Signal) -> Result<()> {
+        // if the cgroup doesn't freeze, the signal is still delivered, only a process
+        // forked meanwhile may miss it
+        let frozen = self.freeze();
+        let signalled = self.procs().map(|procs| {
+            for pid in procs {
+                // the process may have exited in the meantime
+                let _ = kill(pid, signal);
+            }
+        });
+        let thawed = self.thaw();
+        frozen.and(signalled).and(thawed)
+    }
+
+    /// Waits until the cgroup has no processes left, at most `timeout`.
+    /// Killed processes leave the cgroup asynchronously.
+    fn wait_until_empty(&self, timeout: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            match self.procs() {
+                Ok(procs) if !procs.is_empty() => thread::sleep(Duration::from_millis(10)),
+                _ => return,
+            }
+        }
+    }
+
+    /// Reads the current memory usage of the cgroup in bytes from `memory.current`.
+    pub fn memory_current(&self) -> Result<u64> {
+        let memory_current = self.path.join(&self.cgroup).join("memory.current");
+        let content = fs::read_to_string(&memory_current)
+            .with_context(|| format!("Failed to read {:?}", memory_current))?;
+        parse_memory_current(&content)
+            .with_context(|| format!("Invalid value in {:?}", memory_current))
+    }
+
+    /// Reads the current memory usage like [Cgroup::memory_current], None if the memory
+    /// controller is not enabled for the cgroup, which it only is with a memory limit or
+    /// reservation.
+    pub fn memory_usage(&self) -> Result<Option<u64>> {
+        if !self.path.join(&self.cgroup).join("memory.current").exists() {
+            return Ok(None);
+        }
+        self.memory_current().map(Some)
+    }
+
+    /// Reads the number of processes killed by the OOM killer in the cgroup from `memory.events`.
+    pub fn oom_kill_count(&self) -> Result<u64> {
+        let memory_events = self.path.join(&self.cgroup).join("memory.events");
+        let content = fs::read_to_string(&memory_events)
+            .with_context(|| format!("Failed to read {:?}", memory_events))?;
+        parse_oom_kill_count(&content)
+            .with_context(|| format!("Invalid content of {:?}", memory_events))
+    }
+
+    /// Reads the total CPU time consumed by the cgroup in microseconds from `cpu.stat`.
+    pub fn cpu_usage_usec(&self) -> Result<u64> {
+        let cpu_stat = self.path.join(&self.cgroup).join("cpu.stat");
+        let content = fs::read_to_string(&cpu_stat)
+            .with_context(|| format!("Failed to read {:?}", cpu_stat))?;
+        parse_cpu_usage(&content).with_context(|| format!("Invalid content of {:?}", cpu_stat))
+    }
+
     /// Ensures the base cgroup directory exists and controllers are enabled.
-    fn ensure_base_cgroup(&self, need_memory: bool, need_cpu: bool) -> Result<()> {
+    ///
+    /// # Arguments
+    /// * `controller_str` - Controllers to enable (e.g., "+memory +cpu"), may be empty
+    fn ensure_base_cgroup(&self, controller_str: &str) -> Result<()> {
         // Create cgroup directory if it doesn't exist
         let cgroup_dir = self.path.join(&self.cgroup);
         if !cgroup_dir.exists() {
@@ -101,58 +456,317 @@ impl Cgroup {
                 .with_context(|| format!("Failed to create base directory at {:?}", self.path))?;
         }
 
-        // Build controller string
-        let mut controllers = Vec::new();
-        if need_memory {
-            controllers.push("+memory");
+        if !controller_str.is_empty() {
+            // A controller can only be used in a cgroup when it is enabled in the
+            // subtree_control of every ancestor, walk from the root down to the
+            // leaf's parent, the base cgroup
+            let relative = self.path.strip_prefix(&self.root).unwrap_or(Path::new(""));
+            let mut dir = self.root.clone();
+            let _ = fs::write(dir.join("cgroup.subtree_control"), controller_str);
+            for component in relative.components() {
+                dir.push(component);
+                let _ = fs::write(dir.join("cgroup.subtree_control"), controller_str);
+            }
         }
-        if need_cpu {
-            controllers.push("+cpu");
+
+        Ok(())
+    }
+}
+
+impl Limiter for Cgroup {
+    /// Writes the limits to the cgroup's controller files.
+    fn apply(&self, limits: &Limits) -> Result<()> {
+        let cpu = &limits.cpu;
+        let memory = &limits.mem;
+
+        // Apply memory limit if specified
+        if let Some(mem_limit) = memory {
+            // Validate memory limit string before applying
+            validate_memory_limit(mem_limit)?;
+
+            self.set_memory_limit(mem_limit)
+                .with_context(|| format!("Failed to set memory limit to {}", mem_limit))?;
         }
 
-        if !controllers.is_empty() {
-            let controller_str = controllers.join(" ");
+        // Apply memory reservation if specified, it can't exceed the memory limit
+        if let Some(reservation) = &limits.mem_reservation {
+            let reserved = parse_memory_limit(reservation)?;
+            let max = memory
+                .as_deref()
+                .map(parse_memory_limit)
+                .transpose()?
+                .flatten();
+            if let Some(max) = max
+                && reserved.is_none_or(|reserved| reserved > max)
+            {
+                anyhow::bail!(
+                    "Memory reservation {} exceeds the memory limit of {}",
+                    reserved.map_or(String::from("max"), format_bytes),
+                    format_bytes(max)
+                );
+            }
 
-            // Enable controllers in the root cgroup's subtree_control
-            // This allows us to use them in our cgroup
-            let root_subtree_control = Path::new(CGROUP_PATH).join("cgroup.subtree_control");
-            let _ = fs::write(root_subtree_control, &controller_str);
+            self.set_memory_low(reservation)
+                .with_context(|| format!("Failed to set memory reservation to {}", reservation))?;
+        }
+
+        // Apply the process limit if specified
+        if let Some(pids) = limits.pids {
+            self.set_pids_limit(pids)
+                .with_context(|| format!("Failed to set pids limit to {}", pids))?;
+        }
+
+        // Apply swappiness if specified, where the hierarchy supports it
+        if let Some(swappiness) = limits.swappiness {
+            self.set_memory_swappiness(swappiness)
+                .with_context(|| format!("Failed to set memory swappiness to {}", swappiness))?;
+        }
+
+        // Apply CPU limit if specified
+        if let Some(cpu_quota) = cpu {
+            self.set_cpu_limit(cpu_quota)
+                .with_context(|| format!("Failed to set CPU limit to {}", cpu_quota))?;
+        }
+
+        // Apply raw CPU quota and period if specified
+        if let Some(quota) = limits.cpu_quota {
+            let period = limits.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+            self.set_cpu_max(quota, period)
+                .with_context(|| format!("Failed to set CPU quota {} period {}", quota, period))?;
+        } else if limits.cpu_period.is_some() {
+            anyhow::bail!("CPU period requires a CPU quota");
+        }
+
+        // Apply CPU weight derived from the nice value if specified
+        if let Some(nice) = limits.cpu_nice {
+            self.set_cpu_weight(nice_to_weight(nice)?)
+                .with_context(|| format!("Failed to set CPU weight for nice {}", nice))?;
+        }
+
+        // Pin memory allocations to NUMA nodes if specified
+        if let Some(mems) = &limits.cpuset_mems {
+            validate_cpuset_mems(mems, &available_numa_nodes()?)?;
 
-            // Also enable controllers in the silo cgroup's subtree_control
-            // This allows child cgroups to use them
-            let base_subtree_control = self.path.join("cgroup.subtree_control");
-            let _ = fs::write(base_subtree_control, &controller_str);
+            self.set_cpuset_mems(mems)
+                .with_context(|| format!("Failed to set cpuset.mems to {}", mems))?;
         }
 
+        // Limit the sub-cgroups the container can create if specified
+        self.set_cgroup_limits(limits.max_descendants, limits.max_depth)
+            .context("Failed to limit sub-cgroups")?;
+
+        Ok(())
+    }
+
+    /// Adds a process to this cgroup.
+    ///
+    /// # Arguments
+    /// * `pid` - Process ID to add to the cgroup
+    fn add_process(&self, pid: i32) -> Result<()> {
+        let procs_file = self.path.join(&self.cgroup).join("cgroup.procs");
+        fs::write(&procs_file, pid.to_string())
+            .with_context(|| format!("Failed to add process {} to cgroup", pid))?;
         Ok(())
     }
 }
 
 impl Drop for Cgroup {
     fn drop(&mut self) {
-        // remove leaf cgroup
-        let leaf_cgroup = self.path.join(self.cgroup.as_str());
-        let _ = fs::remove_dir(&leaf_cgroup);
-        // remove cgroup
-        let _ = fs::remove_dir(&self.path);
+        // kill processes which escaped the container's init, otherwise the cgroup can't be removed.
+        self.kill_remaining();
+        if self.keep {
+            let leaf = self.path.join(&self.cgroup);
+            println!(
+                "keeping cgroup {:?}, remove it with `rmdir {} {}` when done",
+                leaf,
+                leaf.display(),
+                self.path.display()
+            );
+            return;
+        }
+        // remove the base cgroup with the leaf and any cgroups created below it
+        if let Err(e) = remove_cgroup_tree(&self.path) {
+            eprintln!("warning: failed to remove cgroup: {:#}", e);
+        }
     }
 }
 
-/// Parses a CPU quota decimal (e.g., "0.5") into cgroup format.
+/// Removes a cgroup and all cgroups below it, bottom-up. The cgroups must have no processes.
+///
+/// A cgroup directory is full of pseudo-files which can't be deleted, so `remove_dir_all`
+/// doesn't work, but `rmdir` removes a cgroup without sub-cgroups with its pseudo-files.
+fn remove_cgroup_tree(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut result = Ok(());
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to list {:?}", dir))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir()
+            && let Err(e) = remove_cgroup_tree(&entry.path())
+        {
+            // keep removing the siblings, report the first failure
+            result = result.and(Err(e));
+        }
+    }
+    result?;
+    fs::remove_dir(dir).with_context(|| format!("Failed to remove {:?}", dir))
+}
+
+/// Extracts the `frozen` field from the content of a `cgroup.events` file.
+fn parse_frozen(events: &str) -> Result<bool> {
+    match events.lines().find_map(|line| line.strip_prefix("frozen ")) {
+        Some("1") => Ok(true),
+        Some("0") => Ok(false),
+        _ => anyhow::bail!("frozen not found in cgroup.events"),
+    }
+}
+
+/// Extracts `usage_usec` from the content of a `cpu.stat` file.
+fn parse_cpu_usage(cpu_stat: &str) -> Result<u64> {
+    cpu_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .context("usage_usec not found")?
+        .trim()
+        .parse()
+        .context("usage_usec is not a number")
+}
+
+/// Extracts `oom_kill` from the content of a `memory.events` file.
+fn parse_oom_kill_count(memory_events: &str) -> Result<u64> {
+    memory_events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .context("oom_kill not found")?
+        .trim()
+        .parse()
+        .context("oom_kill is not a number")
+}
+
+/// Lists the controllers needed to apply `limits`.
+pub fn required_controllers(limits: &Limits) -> Vec<&'static str> {
+    let mut controllers = Vec::new();
+    if limits.mem.is_some() || limits.mem_reservation.is_some() {
+        controllers.push("memory");
+    }
+    if limits.cpu.is_some() || limits.cpu_quota.is_some() || limits.cpu_nice.is_some() {
+        controllers.push("cpu");
+    }
+    if limits.cpuset_mems.is_some() {
+        controllers.push("cpuset");
+    }
+    if limits.pids.is_some() {
+        controllers.push("pids");
+    }
+    controllers
+}
+
+/// Builds the `cgroup.subtree_control` string enabling the controllers needed for `limits`.
+fn controller_string(limits: &Limits) -> String {
+    required_controllers(limits)
+        .iter()
+        .map(|controller| format!("+{}", controller))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses a `--cgroup-parent` path, which must stay below the cgroup root (e.g., "batch/jobs").
+pub fn parse_cgroup_parent(value: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(value);
+    let below_root = path
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if value.is_empty() || !below_root {
+        anyhow::bail!(
+            "cgroup parent '{}' must be a relative path without '..'",
+            value
+        );
+    }
+    Ok(path)
+}
+
+/// Parses a cpuset list (e.g., "0-2,4") into the individual numbers.
+fn parse_cpuset_list(list: &str) -> Result<Vec<u32>> {
+    let mut values = Vec::new();
+    for part in list.split(',') {
+        let part = part.trim();
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: u32 = start
+                    .parse()
+                    .with_context(|| format!("Invalid range start in '{}'", part))?;
+                let end: u32 = end
+                    .parse()
+                    .with_context(|| format!("Invalid range end in '{}'", part))?;
+                if start > end {
+                    anyhow::bail!("Invalid range '{}', start is greater than end", part);
+                }
+                values.extend(start..=end);
+            }
+            None => values.push(
+                part.parse()
+                    .with_context(|| format!("Invalid number '{}'", part))?,
+            ),
+        }
+    }
+    Ok(values)
+}
+
+/// Lists NUMA nodes present on the host, as found in `/sys/devices/system/node`.
+fn available_numa_nodes() -> Result<Vec<u32>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir("/sys/devices/system/node").context("Failed to list NUMA nodes")? {
+        let name = entry?.file_name();
+        if let Some(Ok(node)) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("node"))
+            .map(str::parse)
+        {
+            nodes.push(node);
+        }
+    }
+    Ok(nodes)
+}
+
+/// Validates a `cpuset.mems` node list against the nodes available on the host.
+fn validate_cpuset_mems(mems: &str, available: &[u32]) -> Result<()> {
+    let nodes = parse_cpuset_list(mems)
+        .with_context(|| format!("Unsupported NUMA node list '{}'. Use e.g. 0 or 0-1,3", mems))?;
+    if let Some(node) = nodes.iter().find(|node| !available.contains(node)) {
+        anyhow::bail!("NUMA node {} does not exist on this host", node);
+    }
+    Ok(())
+}
+
+/// Parses a CPU quota decimal (e.g., "0.5") or percentage (e.g., "50%") into cgroup format.
 ///
 /// # Arguments
-/// * `cpu` - CPU quota as decimal string (e.g., "0.5" for 50% of one core)
+/// * `cpu` - CPU quota as decimal string (e.g., "0.5" for 50% of one core) or percentage
+///   of one core (e.g., "150%" for one and a half cores)
+/// * `cores` - Number of CPUs available, the quota can't exceed them
 ///
 /// # Returns
 /// A string in the format "quota period" (e.g., "50000 100000")
-fn parse_cpu_quota(cpu: &str) -> Result<String> {
-    let quota_fraction: f64 = cpu
-        .parse()
-        .context("CPU quota must be a valid decimal number")?;
+fn parse_cpu_quota(cpu: &str, cores: usize) -> Result<String> {
+    let quota_fraction: f64 = match cpu.strip_suffix('%') {
+        Some(percent) => {
+            let percent: f64 = percent
+                .parse()
+                .context("CPU quota percentage must be a valid decimal number")?;
+            percent / 100.0
+        }
+        None => cpu
+            .parse()
+            .context("CPU quota must be a valid decimal number")?,
+    };
 
     if quota_fraction <= 0.0 {
         anyhow::bail!("CPU quota must be greater than 0");
     }
+    if quota_fraction > cores as f64 {
+        anyhow::bail!("CPU quota {} exceeds the {} available CPUs", cpu, cores);
+    }
 
     // Standard period is 100ms (100000 microseconds)
     const PERIOD: f64 = 100000.0;
@@ -161,45 +775,848 @@ fn parse_cpu_quota(cpu: &str) -> Result<String> {
     Ok(format!("{} {}", quota, PERIOD))
 }
 
+/// Validates a raw CPU quota and period and formats them for `cpu.max`.
+///
+/// The kernel accepts quotas of at least 1ms and periods between 1ms and 1s.
+///
+/// # Returns
+/// A string in the format "quota period" (e.g., "30000 60000")
+fn format_cpu_max(quota: u64, period: u64) -> Result<String> {
+    if !CPU_PERIOD_RANGE.contains(&period) {
+        anyhow::bail!(
+            "CPU period must be between {} and {} microseconds",
+            CPU_PERIOD_RANGE.start(),
+            CPU_PERIOD_RANGE.end()
+        );
+    }
+    if quota < MIN_CPU_QUOTA {
+        anyhow::bail!("CPU quota must be at least {} microseconds", MIN_CPU_QUOTA);
+    }
+    Ok(format!("{} {}", quota, period))
+}
+
+/// Converts a nice value to a cgroup v2 `cpu.weight`.
+///
+/// Uses the same conversion as the kernel does for `cpu.weight.nice`: the nice value is
+/// mapped to a scheduler load weight, which is scaled so that 1024 (nice 0) becomes 100
+/// and clamped to 1..10000.
+///
+/// # Returns
+/// The weight, e.g. 100 for nice 0, 8668 for nice -20 and 1 for nice 19
+pub fn nice_to_weight(nice: i32) -> Result<u32> {
+    if !NICE_RANGE.contains(&nice) {
+        anyhow::bail!(
+            "nice value {} is out of range {}..{}",
+            nice,
+            NICE_RANGE.start(),
+            NICE_RANGE.end()
+        );
+    }
+    let load_weight = NICE_TO_LOAD_WEIGHT[(nice - NICE_RANGE.start()) as usize];
+    // round to the closest value like DIV_ROUND_CLOSEST_ULL
+    let weight = (load_weight * 100 + 512) / 1024;
+    Ok(weight.clamp(1, 10000) as u32)
+}
+
+/// Parses a `--pids` limit, a positive number of processes.
+pub fn parse_pids_limit(value: &str) -> Result<u64> {
+    let limit: u64 = value
+        .parse()
+        .with_context(|| format!("pids limit '{}' is not a number of processes", value))?;
+    if limit == 0 {
+        anyhow::bail!("pids limit must be at least 1, the container needs its init process");
+    }
+    Ok(limit)
+}
+
 /// Validates a memory limit string for cgroup v2 `memory.max`.
 ///
 /// Supported formats:
 /// - "max" (no limit)
 /// - Decimal number of bytes (e.g., "1048576")
-/// - Number with unit suffix: K, M, G (decimal: 10^3, 10^6, 10^9)
-/// - Number with IEC unit suffix: Ki, Mi, Gi (binary: 2^10, 2^20, 2^30)
+/// - Number with one of [MEMORY_UNITS] as suffix, case-insensitive
 ///
-/// Examples of valid inputs: "max", "1024", "512K", "128Ki", "100M", "2Gi"
+/// Examples of valid inputs: "max", "1024", "512K", "100M", "2Gb"
 ///
 /// Returns Ok(()) if valid, Err otherwise.
 fn validate_memory_limit(limit: &str) -> Result<()> {
-    let re = regex::Regex::new(r"^(?i:max|[1-9]\d*|[1-9]\d*(?:k|m|g|kb|mb|gb))$")?;
+    let re = regex::Regex::new(&format!(
+        r"^(?i:max|[1-9]\d*|[1-9]\d*(?:{}))$",
+        MEMORY_UNITS.join("|")
+    ))?;
     if !re.is_match(limit) {
         anyhow::bail!(
-            "Unsupported memory limit '{}'. Use: max, bytes, or units K/M/G/Kb/Mb/Gb",
-            limit
+            "Unsupported memory limit '{}'. Use: max, bytes, or units {}",
+            limit,
+            MEMORY_UNITS.join("/")
         );
     }
     Ok(())
 }
 
+/// Converts a memory limit to bytes, `None` stands for "max". Units are powers of 1024,
+/// like the kernel interprets them.
+fn parse_memory_limit(limit: &str) -> Result<Option<u64>> {
+    validate_memory_limit(limit)?;
+    let lower = limit.to_ascii_lowercase();
+    if lower == "max" {
+        return Ok(None);
+    }
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &lower[digits.len()..] {
+        "" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        _ => 1 << 30,
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .map(Some)
+        .with_context(|| format!("Memory limit '{}' is too large", limit))
+}
+
+/// Parses the content of `memory.current`, a number of bytes followed by a newline
+fn parse_memory_current(content: &str) -> Result<u64> {
+    let value = content.trim();
+    value
+        .parse()
+        .with_context(|| format!("'{}' is not a number of bytes", value))
+}
+
+/// Binary units used by [format_bytes], each 1024 times the previous one
+const BYTE_UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Formats a byte count for people, e.g. "512 B" or "134.2 MiB"
+pub fn format_bytes(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < BYTE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, BYTE_UNITS[unit])
+    }
+}
+
+/// Reads the total memory in bytes from the contents of `/proc/meminfo`
+fn parse_mem_total(meminfo: &str) -> Result<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb << 10)
+        .context("No MemTotal in meminfo")
+}
+
+/// Describes the problem with a memory `limit` on a host with `host_total` bytes of RAM,
+/// if it has one
+fn memory_limit_warning(name: &str, limit: &str, host_total: u64) -> Result<Option<String>> {
+    Ok(parse_memory_limit(limit)?
+        .filter(|bytes| *bytes > host_total)
+        .map(|_| {
+            format!(
+                "{} {} exceeds the host's {} of memory",
+                name,
+                limit,
+                format_bytes(host_total)
+            )
+        }))
+}
+
+/// Checks the memory limits against the host's RAM before anything is set up. A limit
+/// above it never takes effect and the container is OOM killed by the host instead.
+/// Prints a warning, or fails when `strict` is set.
+pub fn check_host_memory(limits: &Limits, strict: bool) -> Result<()> {
+    let requested = [
+        ("memory limit", &limits.mem),
+        ("memory reservation", &limits.mem_reservation),
+    ];
+    if requested.iter().all(|(_, limit)| limit.is_none()) {
+        return Ok(());
+    }
+    let meminfo = fs::read_to_string(MEMINFO_PATH)
+        .with_context(|| format!("Failed to read {}", MEMINFO_PATH))?;
+    let host_total = parse_mem_total(&meminfo)?;
+    for (name, limit) in requested {
+        if let Some(limit) = limit
+            && let Some(warning) = memory_limit_warning(name, limit, host_total)?
+        {
+            if strict {
+                anyhow::bail!(warning);
+            }
+            eprintln!("warning: {}", warning);
+        }
+    }
+    Ok(())
+}
+
+/// Describes the formats accepted by the resource limit flags, built from the same
+/// constants the validation uses.
+pub fn limits_help() -> String {
+    format!(
+        "Resource limit formats:
+
+  --mem LIMIT
+      max (no limit), a number of bytes or a number with one of the units
+      {units} (case-insensitive). Examples: max, 1048576, 512K, 100M, 2Gb
+
+  --memory-reservation LIMIT
+      Same format as --mem, written to memory.low. Must not exceed --mem
+
+  --cpu QUOTA
+      Share of one CPU as a decimal or a percentage, greater than 0 and at most
+      the number of CPUs. Examples: 0.5, 2, 50%, 150%
+
+  --cpu-quota MICROSECONDS [--cpu-period MICROSECONDS]
+      Raw cpu.max values. The quota is at least {min_quota}, the period is
+      {period_min}..{period_max} and defaults to {default_period}
+
+  --cpu-nice NICE
+      CPU weight as a nice value {nice_min}..{nice_max}, 0 is the default weight of 100
+
+  --cpuset-mems NODES
+      Comma-separated NUMA nodes and ranges. Examples: 0, 0-1,3
+
+  --pids COUNT
+      Most processes and threads in the container at once, at least 1. Example: 100
+
+  --memory-swappiness VALUE
+      {swappiness_min}..{swappiness_max}, written to memory.swappiness. cgroup v2 has no per-cgroup
+      swappiness, there the option fails and memory.swap.max is the alternative
+
+  --cgroup-max-descendants COUNT, --cgroup-max-depth DEPTH
+      Most cgroups the container may create below its own, and how deep they may
+      be nested. Examples: 0 (none), 16
+",
+        units = MEMORY_UNITS.join(", "),
+        min_quota = MIN_CPU_QUOTA,
+        period_min = CPU_PERIOD_RANGE.start(),
+        period_max = CPU_PERIOD_RANGE.end(),
+        default_period = DEFAULT_CPU_PERIOD,
+        swappiness_min = SWAPPINESS_RANGE.start(),
+        swappiness_max = SWAPPINESS_RANGE.end(),
+        nice_min = NICE_RANGE.start(),
+        nice_max = NICE_RANGE.end(),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use nix::sys::signal::{SigSet, SigmaskHow};
 
     #[test]
     fn test_parse_cpu_quota() {
-        assert_eq!(parse_cpu_quota("0.5").unwrap(), "50000 100000");
-        assert_eq!(parse_cpu_quota("1.0").unwrap(), "100000 100000");
-        assert_eq!(parse_cpu_quota("2.0").unwrap(), "200000 100000");
-        assert_eq!(parse_cpu_quota("0.25").unwrap(), "25000 100000");
+        assert_eq!(parse_cpu_quota("0.5", 4).unwrap(), "50000 100000");
+        assert_eq!(parse_cpu_quota("1.0", 4).unwrap(), "100000 100000");
+        assert_eq!(parse_cpu_quota("2.0", 4).unwrap(), "200000 100000");
+        assert_eq!(parse_cpu_quota("0.25", 4).unwrap(), "25000 100000");
+    }
+
+    #[test]
+    fn test_parse_cpu_quota_percentage() {
+        assert_eq!(parse_cpu_quota("50%", 4).unwrap(), "50000 100000");
+        assert_eq!(parse_cpu_quota("150%", 4).unwrap(), "150000 100000");
+        assert_eq!(parse_cpu_quota("400%", 4).unwrap(), "400000 100000");
+        assert!(parse_cpu_quota("0%", 4).is_err());
+        assert!(parse_cpu_quota("401%", 4).is_err());
+        assert!(parse_cpu_quota("%", 4).is_err());
+        assert!(parse_cpu_quota("half%", 4).is_err());
     }
 
     #[test]
     fn test_parse_cpu_quota_invalid() {
-        assert!(parse_cpu_quota("invalid").is_err());
-        assert!(parse_cpu_quota("0").is_err());
-        assert!(parse_cpu_quota("-0.5").is_err());
+        assert!(parse_cpu_quota("invalid", 4).is_err());
+        assert!(parse_cpu_quota("0", 4).is_err());
+        assert!(parse_cpu_quota("-0.5", 4).is_err());
+        assert!(parse_cpu_quota("4.5", 4).is_err());
+    }
+
+    #[test]
+    fn test_format_cpu_max() {
+        assert_eq!(format_cpu_max(30000, 60000).unwrap(), "30000 60000");
+        assert_eq!(format_cpu_max(200000, 100000).unwrap(), "200000 100000");
+        assert!(format_cpu_max(999, 100000).is_err());
+        assert!(format_cpu_max(30000, 999).is_err());
+        assert!(format_cpu_max(30000, 1_000_001).is_err());
+    }
+
+    /// Creates an empty directory standing in for the cgroup hierarchy
+    fn fake_cgroup_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "toy_container_cgroup_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("cgroup.controllers"),
+            "cpuset cpu io memory hugetlb pids rdma misc\n",
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn test_available_controllers() {
+        let root = fake_cgroup_root("controllers");
+        assert_eq!(
+            Cgroup::available_controllers(&root).unwrap(),
+            [
+                "cpuset", "cpu", "io", "memory", "hugetlb", "pids", "rdma", "misc"
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(Cgroup::available_controllers(Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn test_new_fails_early_without_controller() {
+        let root = fake_cgroup_root("missing_controller");
+        fs::write(root.join("cgroup.controllers"), "memory pids\n").unwrap();
+        let limits = Limits {
+            cpu: Some(String::from("0.5")),
+            ..Limits::default()
+        };
+        let err = Cgroup::new(&root, None, &limits).err().unwrap();
+        assert!(
+            err.to_string()
+                .contains("cgroup controller cpu is not available")
+        );
+        assert!(!root.join("toy_container").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_new_with_custom_root() {
+        let root = fake_cgroup_root("custom_root");
+        let limits = Limits {
+            mem: Some(String::from("64M")),
+            cpu: Some(String::from("0.5")),
+            ..Limits::default()
+        };
+        let cgroup = Cgroup::new(&root, None, &limits).unwrap();
+        cgroup.apply(&limits).unwrap();
+
+        let leaf = root.join("toy_container/leaf");
+        assert_eq!(fs::read_to_string(leaf.join("memory.max")).unwrap(), "64M");
+        assert_eq!(
+            fs::read_to_string(leaf.join("cpu.max")).unwrap(),
+            "50000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("cgroup.subtree_control")).unwrap(),
+            "+memory +cpu"
+        );
+
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_new_with_nested_parent() {
+        let root = fake_cgroup_root("nested");
+        let limits = Limits {
+            mem: Some(String::from("64M")),
+            ..Limits::default()
+        };
+        let cgroup = Cgroup::new(&root, Some(Path::new("outer/inner")), &limits).unwrap();
+        cgroup.apply(&limits).unwrap();
+
+        let leaf = root.join("outer/inner/toy_container/leaf");
+        assert_eq!(fs::read_to_string(leaf.join("memory.max")).unwrap(), "64M");
+        for ancestor in ["", "outer", "outer/inner", "outer/inner/toy_container"] {
+            assert_eq!(
+                fs::read_to_string(root.join(ancestor).join("cgroup.subtree_control")).unwrap(),
+                "+memory",
+                "controllers not enabled in {:?}",
+                ancestor
+            );
+        }
+        assert!(!leaf.join("cgroup.subtree_control").exists());
+
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cgroup_parent() {
+        assert_eq!(
+            parse_cgroup_parent("batch/jobs").unwrap(),
+            PathBuf::from("batch/jobs")
+        );
+        assert!(parse_cgroup_parent("").is_err());
+        assert!(parse_cgroup_parent("/batch").is_err());
+        assert!(parse_cgroup_parent("batch/../..").is_err());
+    }
+
+    #[test]
+    fn test_remove_cgroup_tree() {
+        let root = fake_cgroup_root("remove_tree");
+        let base = root.join("toy_container");
+        fs::create_dir_all(base.join("leaf/nested/deeper")).unwrap();
+        fs::create_dir_all(base.join("sibling")).unwrap();
+
+        remove_cgroup_tree(&base).unwrap();
+        assert!(!base.exists());
+        // already removed
+        remove_cgroup_tree(&base).unwrap();
+
+        // a regular file stands in for a cgroup which still has processes
+        fs::create_dir_all(base.join("leaf/busy")).unwrap();
+        fs::create_dir_all(base.join("other")).unwrap();
+        fs::write(base.join("leaf/busy/cgroup.procs"), "1").unwrap();
+        assert!(remove_cgroup_tree(&base).is_err());
+        assert!(base.join("leaf/busy").exists());
+        assert!(!base.join("other").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_memory_limit() {
+        assert_eq!(parse_memory_limit("max").unwrap(), None);
+        assert_eq!(parse_memory_limit("1024").unwrap(), Some(1024));
+        assert_eq!(parse_memory_limit("512k").unwrap(), Some(512 << 10));
+        assert_eq!(parse_memory_limit("64Mb").unwrap(), Some(64 << 20));
+        assert_eq!(parse_memory_limit("2G").unwrap(), Some(2 << 30));
+        assert!(parse_memory_limit("64T").is_err());
+        assert!(parse_memory_limit("99999999999999999G").is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(140_718_899), "134.2 MiB");
+        assert_eq!(format_bytes(u64::MAX), "16777216.0 TiB");
+        for (limit, formatted) in [
+            ("512K", "512.0 KiB"),
+            ("128M", "128.0 MiB"),
+            ("2Gb", "2.0 GiB"),
+        ] {
+            let bytes = parse_memory_limit(limit).unwrap().unwrap();
+            assert_eq!(format_bytes(bytes), formatted);
+        }
+    }
+
+    #[test]
+    fn test_parse_memory_current() {
+        assert_eq!(parse_memory_current("4096\n").unwrap(), 4096);
+        assert!(parse_memory_current("").is_err());
+        assert!(parse_memory_current("max\n").is_err());
+    }
+
+    #[test]
+    fn test_memory_limit_warning() {
+        let meminfo = "MemTotal:        8048576 kB\nMemFree:         1234567 kB\n";
+        let host_total = parse_mem_total(meminfo).unwrap();
+        assert_eq!(host_total, 8048576 << 10);
+        assert!(parse_mem_total("MemFree: 1 kB\n").is_err());
+
+        assert_eq!(
+            memory_limit_warning("memory limit", "100G", host_total).unwrap(),
+            Some(String::from(
+                "memory limit 100G exceeds the host's 7.7 GiB of memory"
+            ))
+        );
+        assert_eq!(
+            memory_limit_warning("memory limit", "512M", host_total).unwrap(),
+            None
+        );
+        assert_eq!(
+            memory_limit_warning("memory limit", "max", host_total).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_memory_reservation() {
+        let root = fake_cgroup_root("reservation");
+        let limits = Limits {
+            mem: Some(String::from("128M")),
+            mem_reservation: Some(String::from("64M")),
+            ..Limits::default()
+        };
+        let cgroup = Cgroup::new(&root, None, &limits).unwrap();
+        cgroup.apply(&limits).unwrap();
+        let leaf = root.join("toy_container/leaf");
+        assert_eq!(
+            fs::read_to_string(leaf.join("memory.low")).unwrap(),
+            "67108864"
+        );
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+
+        let root = fake_cgroup_root("reservation_too_large");
+        let limits = Limits {
+            mem: Some(String::from("64M")),
+            mem_reservation: Some(String::from("1G")),
+            ..Limits::default()
+        };
+        let cgroup = Cgroup::new(&root, None, &limits).unwrap();
+        assert_eq!(
+            cgroup.apply(&limits).unwrap_err().to_string(),
+            "Memory reservation 1.0 GiB exceeds the memory limit of 64.0 MiB"
+        );
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_set_limits_write_expected_files() {
+        let root = fake_cgroup_root("set_limits");
+        let cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        let leaf = root.join("toy_container/leaf");
+
+        cgroup.set_memory_limit("128M").unwrap();
+        assert_eq!(fs::read_to_string(leaf.join("memory.max")).unwrap(), "128M");
+
+        cgroup.set_cpu_limit("0.25").unwrap();
+        assert_eq!(
+            fs::read_to_string(leaf.join("cpu.max")).unwrap(),
+            "25000 100000"
+        );
+
+        cgroup.set_cpu_max(30000, 60000).unwrap();
+        assert_eq!(
+            fs::read_to_string(leaf.join("cpu.max")).unwrap(),
+            "30000 60000"
+        );
+
+        cgroup.add_process(1234).unwrap();
+        assert_eq!(
+            fs::read_to_string(leaf.join("cgroup.procs")).unwrap(),
+            "1234"
+        );
+        // the fake process never leaves, don't wait for it on drop
+        fs::write(leaf.join("cgroup.procs"), "").unwrap();
+
+        // no controllers requested, subtree_control is left alone
+        assert!(!root.join("cgroup.subtree_control").exists());
+
+        cgroup.set_pids_limit(64).unwrap();
+        assert_eq!(fs::read_to_string(leaf.join("pids.max")).unwrap(), "64");
+
+        // fake v2 hierarchy without memory.swappiness
+        let err = cgroup.set_memory_swappiness(10).unwrap_err();
+        assert!(err.to_string().contains("memory.swap.max"));
+        fs::write(leaf.join("memory.swappiness"), "60").unwrap();
+        cgroup.set_memory_swappiness(10).unwrap();
+        assert_eq!(
+            fs::read_to_string(leaf.join("memory.swappiness")).unwrap(),
+            "10"
+        );
+
+        cgroup.set_cgroup_limits(Some(4), None).unwrap();
+        assert_eq!(
+            fs::read_to_string(leaf.join("cgroup.max.descendants")).unwrap(),
+            "4"
+        );
+        assert!(!leaf.join("cgroup.max.depth").exists());
+        cgroup.set_cgroup_limits(None, Some(2)).unwrap();
+        assert_eq!(
+            fs::read_to_string(leaf.join("cgroup.max.depth")).unwrap(),
+            "2"
+        );
+
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_freeze_thaw_kill() {
+        let root = fake_cgroup_root("freeze");
+        let cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        let leaf = root.join("toy_container/leaf");
+
+        // the fake freezer is frozen right away
+        fs::write(leaf.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+        cgroup.freeze().unwrap();
+        assert!(cgroup.is_frozen().unwrap());
+        fs::write(leaf.join("cgroup.events"), "populated 1\nfrozen 0\n").unwrap();
+        cgroup.thaw().unwrap();
+        assert!(!cgroup.is_frozen().unwrap());
+
+        cgroup.kill_all().unwrap();
+        assert_eq!(fs::read_to_string(leaf.join("cgroup.kill")).unwrap(), "1");
+
+        fs::write(leaf.join("cgroup.procs"), "12\n34\n").unwrap();
+        assert_eq!(
+            cgroup.procs().unwrap(),
+            vec![Pid::from_raw(12), Pid::from_raw(34)]
+        );
+        fs::write(leaf.join("cgroup.procs"), "").unwrap();
+
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Mount point of the host's cgroup v2 hierarchy, None if there is none or the tests
+    /// can't create cgroups in it
+    fn real_cgroup_root() -> Option<PathBuf> {
+        if unsafe { libc::geteuid() } != 0 {
+            return None;
+        }
+        let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+        mounts.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?;
+            (fields.next()? == "cgroup2").then(|| PathBuf::from(mount_point))
+        })
+    }
+
+    /// Forks `count` children which wait for a signal in a new cgroup below `root`. With
+    /// `ignore_sigterm` they start with SIGTERM blocked, so they never receive it.
+    fn cgroup_with_children(
+        root: &Path,
+        name: &str,
+        count: usize,
+        ignore_sigterm: bool,
+    ) -> (Cgroup, PathBuf, Vec<Pid>) {
+        let parent = PathBuf::from(format!(
+            "toy_container_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let cgroup = Cgroup::new(root, Some(&parent), &Limits::default()).unwrap();
+        let mut sigterm = SigSet::empty();
+        if ignore_sigterm {
+            sigterm.add(Signal::SIGTERM);
+        }
+        let previous = sigterm.thread_swap_mask(SigmaskHow::SIG_BLOCK).unwrap();
+        let children = (0..count)
+            .map(|_| match unsafe { nix::unistd::fork() }.unwrap() {
+                nix::unistd::ForkResult::Child => unsafe {
+                    libc::alarm(10);
+                    loop {
+                        libc::pause();
+                    }
+                },
+                nix::unistd::ForkResult::Parent { child } => {
+                    cgroup.add_process(child.as_raw()).unwrap();
+                    child
+                }
+            })
+            .collect();
+        previous.thread_set_mask().unwrap();
+        (cgroup, root.join(parent), children)
+    }
+
+    /// Signal which ended `child`
+    fn wait_for_signal(child: Pid) -> Option<Signal> {
+        match nix::sys::wait::waitpid(child, None).unwrap() {
+            nix::sys::wait::WaitStatus::Signaled(_, signal, _) => Some(signal),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_kill_all_terminates_processes() {
+        let Some(root) = real_cgroup_root() else {
+            return;
+        };
+        let (cgroup, parent, children) = cgroup_with_children(&root, "kill_all", 3, true);
+        assert_eq!(cgroup.procs().unwrap().len(), 3);
+
+        cgroup.kill_all().unwrap();
+        for child in children {
+            assert_eq!(wait_for_signal(child), Some(Signal::SIGKILL));
+        }
+        assert!(cgroup.procs().unwrap().is_empty());
+
+        drop(cgroup);
+        fs::remove_dir(parent).unwrap();
+    }
+
+    #[test]
+    fn test_signal_all_then_kill() {
+        let Some(root) = real_cgroup_root() else {
+            return;
+        };
+        let (cgroup, parent, children) = cgroup_with_children(&root, "signal_all", 2, false);
+        cgroup.signal_all(Signal::SIGTERM).unwrap();
+        assert!(!cgroup.is_frozen().unwrap());
+        for child in children {
+            assert_eq!(wait_for_signal(child), Some(Signal::SIGTERM));
+        }
+
+        // processes ignoring the stop signal are drained by the kill
+        let (ignoring, ignoring_parent, children) =
+            cgroup_with_children(&root, "signal_all_ignored", 2, true);
+        ignoring.signal_all(Signal::SIGTERM).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(ignoring.procs().unwrap().len(), 2);
+        ignoring.kill_all().unwrap();
+        for child in children {
+            assert_eq!(wait_for_signal(child), Some(Signal::SIGKILL));
+        }
+
+        drop(cgroup);
+        drop(ignoring);
+        fs::remove_dir(parent).unwrap();
+        fs::remove_dir(ignoring_parent).unwrap();
+    }
+
+    #[test]
+    fn test_freeze_waits_for_frozen_state() {
+        let root = fake_cgroup_root("freeze_wait");
+        let cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        let leaf = root.join("toy_container/leaf");
+        let events = leaf.join("cgroup.events");
+        fs::write(&events, "populated 1\nfrozen 0\n").unwrap();
+
+        // the kernel reports the frozen state some time after cgroup.freeze is written
+        let freezer = {
+            let events = events.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                fs::write(&events, "populated 1\nfrozen 1\n").unwrap();
+            })
+        };
+        let start = Instant::now();
+        cgroup.freeze().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(cgroup.is_frozen().unwrap());
+        assert_eq!(fs::read_to_string(leaf.join("cgroup.freeze")).unwrap(), "1");
+        freezer.join().unwrap();
+
+        // already frozen, returns without writing
+        fs::remove_file(leaf.join("cgroup.freeze")).unwrap();
+        cgroup.freeze().unwrap();
+        assert!(!leaf.join("cgroup.freeze").exists());
+
+        drop(cgroup);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_frozen() {
+        assert!(parse_frozen("populated 1\nfrozen 1\n").unwrap());
+        assert!(!parse_frozen("populated 0\nfrozen 0\n").unwrap());
+        assert!(parse_frozen("populated 1\n").is_err());
+    }
+
+    #[test]
+    fn test_drop_kills_remaining_processes() {
+        let root = fake_cgroup_root("drop_kill");
+        let cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        drop(cgroup);
+
+        // the fake cgroup can't be removed because it holds regular files
+        let leaf = root.join("toy_container/leaf");
+        assert_eq!(fs::read_to_string(leaf.join("cgroup.kill")).unwrap(), "1");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_drop_keeps_cgroup() {
+        let root = fake_cgroup_root("keep");
+        let mut cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        let leaf = root.join("toy_container/leaf");
+        // an empty cgroup can be removed with rmdir
+        for entry in fs::read_dir(&leaf).unwrap() {
+            fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        cgroup.keep_after_drop();
+        drop(cgroup);
+
+        assert!(leaf.is_dir());
+        assert_eq!(fs::read_to_string(leaf.join("cgroup.kill")).unwrap(), "1");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cpu_usage() {
+        let stat = "usage_usec 123456\nuser_usec 100000\nsystem_usec 23456\n";
+        assert_eq!(parse_cpu_usage(stat).unwrap(), 123456);
+        assert!(parse_cpu_usage("user_usec 1\n").is_err());
+        assert!(parse_cpu_usage("usage_usec abc\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_oom_kill_count() {
+        let events = "low 0\nhigh 0\nmax 4\noom 2\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kill_count(events).unwrap(), 1);
+        assert!(parse_oom_kill_count("oom 2\n").is_err());
+    }
+
+    #[test]
+    fn test_limits_help() {
+        let help = limits_help();
+        assert!(help.contains("max (no limit)"));
+        for unit in MEMORY_UNITS {
+            assert!(help.contains(unit));
+        }
+        assert!(help.contains("50%"));
+        assert!(help.contains("-20..19"));
+    }
+
+    #[test]
+    fn test_nice_to_weight() {
+        assert_eq!(nice_to_weight(0).unwrap(), 100);
+        assert_eq!(nice_to_weight(-20).unwrap(), 8668);
+        assert_eq!(nice_to_weight(-10).unwrap(), 932);
+        assert_eq!(nice_to_weight(5).unwrap(), 33);
+        assert_eq!(nice_to_weight(19).unwrap(), 1);
+        assert!(nice_to_weight(-21).is_err());
+        assert!(nice_to_weight(20).is_err());
+    }
+
+    #[test]
+    fn test_controller_string() {
+        assert_eq!(controller_string(&Limits::default()), "");
+        let limits = Limits {
+            mem: Some(String::from("64M")),
+            cpu_quota: Some(50000),
+            cpuset_mems: Some(String::from("0")),
+            ..Limits::default()
+        };
+        assert_eq!(controller_string(&limits), "+memory +cpu +cpuset");
+
+        let limits = Limits {
+            pids: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(controller_string(&limits), "+pids");
+    }
+
+    #[test]
+    fn test_parse_pids_limit() {
+        assert_eq!(parse_pids_limit("1").unwrap(), 1);
+        assert_eq!(parse_pids_limit("4096").unwrap(), 4096);
+        assert!(parse_pids_limit("0").is_err());
+        assert!(parse_pids_limit("-1").is_err());
+        assert!(parse_pids_limit("many").is_err());
+        assert!(parse_pids_limit("").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpuset_list() {
+        assert_eq!(parse_cpuset_list("0").unwrap(), vec![0]);
+        assert_eq!(parse_cpuset_list("0-2,4").unwrap(), vec![0, 1, 2, 4]);
+        assert!(parse_cpuset_list("").is_err());
+        assert!(parse_cpuset_list("2-1").is_err());
+        assert!(parse_cpuset_list("a").is_err());
+    }
+
+    #[test]
+    fn test_validate_cpuset_mems() {
+        assert!(validate_cpuset_mems("0", &[0, 1]).is_ok());
+        assert!(validate_cpuset_mems("0-1", &[0, 1]).is_ok());
+        assert!(validate_cpuset_mems("2", &[0, 1]).is_err());
+        assert!(validate_cpuset_mems("0-3", &[0, 1]).is_err());
+        assert!(validate_cpuset_mems("x", &[0, 1]).is_err());
     }
 
     #[test]
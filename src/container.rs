@@ -1,6 +1,14 @@
 use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
     net::Ipv4Addr,
-    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -9,39 +17,532 @@ use caps::{CapSet, Capability};
 use cidr::Ipv4Cidr;
 use libc::{getegid, geteuid};
 use nix::{
+    fcntl::{FcntlArg, FdFlag, OFlag, fcntl},
     sched::{CloneFlags, clone},
     sys::{
-        signal::{SigSet, Signal, kill},
+        signal::{SigSet, SigmaskHow, Signal, kill},
         signalfd::SignalFd,
-        wait::{WaitPidFlag, WaitStatus, waitpid},
+        stat::{Mode, umask},
+        wait::{Id, WaitPidFlag, WaitStatus, waitid, waitpid},
     },
-    unistd::{ForkResult, Pid, close, fork, pipe, read, sethostname, write},
+    unistd::{ForkResult, Pid, close, fork, pipe2, read, sethostname, write},
 };
+use serde::Serialize;
 
+use crate::events::{Event, EventSink};
+use crate::hooks;
+use crate::limiter::{NoopLimiter, limit_process};
 use crate::net;
-use crate::{cgroups::Cgroup, fs};
+use crate::retry::{is_transient, retry};
+use crate::sampler;
+use crate::{
+    cgroups::{CGROUP_PATH, Cgroup, Limits},
+    fs,
+};
 
 const STACK_SIZE: usize = 1024 * 1024;
+/// Directory below the fs root the overlay directories of failed runs are moved to
+const FAILED_RUNS_DIR: &str = "failed";
+/// Directory below the fs root the overlay directories of interrupted runs are moved to
+const INTERRUPTED_RUNS_DIR: &str = "interrupted";
 
-struct ContainerConfig {
-    is_parent_root: bool,
-    network_cidr: Ipv4Cidr,
-    hostname: Option<String>,
-    drop_caps: bool,
+/// Delay before retrying a failed container launch, doubled after every attempt up to a cap
+const STARTUP_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Container settings, resolved from the command line by the binary. [Default] gives the
+/// settings the command line uses without options
+pub struct ContainerConfig {
+    pub hostname: Option<String>,
+    pub domainname: Option<String>,
+    /// Extra names resolving to the container's address in /etc/hosts
+    pub network_aliases: Vec<String>,
+    /// Resolver settings written to /etc/resolv.conf
+    pub dns: net::DnsConfig,
+    /// Namespaced sysctls set inside the container
+    pub sysctls: Vec<(String, String)>,
+    pub drop_caps: bool,
+    /// Expose /dev/fuse and keep the capability needed to mount FUSE filesystems
+    pub enable_fuse: bool,
+    /// Capabilities kept in the bounding set when dropping capabilities
+    pub cap_keep: Vec<Capability>,
+    pub limits: Limits,
+    /// Mount point of the cgroup v2 hierarchy
+    pub cgroup_root: PathBuf,
+    /// Cgroup relative to `cgroup_root` the container's cgroup is created in
+    pub cgroup_parent: Option<PathBuf>,
+    /// Signal delivered to the command when the container is asked to stop
+    pub stop_signal: Signal,
+    /// Time the command gets to exit after the stop signal before it is killed
+    pub stop_timeout: Duration,
+    /// Host bridge the container's veth is attached to
+    pub bridge: String,
+    /// Shell command run on the host after the container is set up, before its command starts
+    pub prestart_hook: Option<String>,
+    /// Shell command run on the host after the container is torn down
+    pub poststop_hook: Option<String>,
+    /// OOM score adjustment of the container's init, inherited by its processes
+    pub oom_score_adj: Option<i32>,
+    /// Keep the overlay directories of a run which exited with a non-zero code
+    pub preserve_on_failure: bool,
+    /// Keep the overlay directories, including the writes in the upper layer, of a run which
+    /// was stopped by SIGINT or SIGTERM
+    pub keep_upper_on_signal: bool,
+    /// Leave the cgroup in place after the run for inspection
+    pub keep_cgroup: bool,
+    /// Network namespace the container joins instead of getting its own, e.g. /run/netns/NAME
+    pub netns: Option<PathBuf>,
+    /// Default gateway of the container, the bridge address if not set
+    pub gateway: Option<Ipv4Addr>,
+    /// Kill the container if it is still running after this long
+    pub timeout: Option<Duration>,
+    pub fs: fs::FsConfig,
+    /// Namespaces created for the container, see [parse_isolate]
+    pub namespaces: CloneFlags,
+    /// Seconds added to the monotonic and boot time clocks seen in the container
+    pub time_offset: Option<i64>,
+    /// Freeze the cgroup while delivering the stop signal to all of its processes
+    pub freeze_on_stop: bool,
+    /// Number of times a failed container launch is retried
+    pub max_startup_retries: u32,
+    /// Interval for sampling the container's resource usage, no sampling if not set
+    pub sample_interval: Option<Duration>,
+    /// File receiving the resource usage samples, stdout if not set
+    pub sample_output: Option<PathBuf>,
+    /// Warn when the container makes no progress for this long, see [sampler::ProgressCheck]
+    pub progress_watchdog: Option<Duration>,
+    /// Metadata attached to the container, reported in the run summary
+    pub labels: Vec<(String, String)>,
+    /// Disable address space layout randomization for the command
+    pub no_aslr: bool,
+    /// File mode creation mask of the command
+    pub umask: libc::mode_t,
+    /// AppArmor profile the command is confined by
+    pub apparmor_profile: Option<String>,
+    /// Host file descriptors passed on to the command
+    pub preserve_fds: Vec<RawFd>,
+    /// Host file receiving the command's stdout and stderr
+    pub log_file: Option<PathBuf>,
+    /// Unix socket receiving lifecycle events as JSON lines
+    pub events_socket: Option<PathBuf>,
+    /// File the container ID is written to
+    pub cidfile: Option<PathBuf>,
+    /// Replace an existing cidfile
+    pub force_cidfile: bool,
 }
 
-fn child(command: &str, args: &[String], config: &ContainerConfig) -> anyhow::Result<()> {
-    if !config.is_parent_root {
-        fs::create_overlay_dirs("fs")?;
+impl ContainerConfig {
+    /// Whether the container gets its own namespace of the given type
+    fn isolates(&self, namespace: CloneFlags) -> bool {
+        self.namespaces.contains(namespace)
+    }
+
+    /// Whether the container gets a new network namespace, connected to the bridge when
+    /// running as root
+    fn creates_network(&self) -> bool {
+        self.isolates(CloneFlags::CLONE_NEWNET) && self.netns.is_none()
     }
-    fs::create_container_filesystem("fs")?;
+}
 
-    net::bring_up_container_net(&config.network_cidr, config.is_parent_root)?;
+impl Default for ContainerConfig {
+    /// The settings the command line uses when no options are given
+    fn default() -> Self {
+        ContainerConfig {
+            hostname: None,
+            domainname: None,
+            network_aliases: Vec::new(),
+            dns: net::DnsConfig::default(),
+            sysctls: Vec::new(),
+            drop_caps: false,
+            enable_fuse: false,
+            cap_keep: Vec::new(),
+            limits: Limits::default(),
+            cgroup_root: PathBuf::from(CGROUP_PATH),
+            cgroup_parent: None,
+            stop_signal: Signal::SIGTERM,
+            stop_timeout: Duration::from_secs(10),
+            bridge: net::DEFAULT_BRIDGE.to_string(),
+            prestart_hook: None,
+            poststop_hook: None,
+            oom_score_adj: None,
+            preserve_on_failure: false,
+            keep_upper_on_signal: false,
+            keep_cgroup: false,
+            netns: None,
+            gateway: None,
+            timeout: None,
+            fs: fs::FsConfig {
+                tmpfs_size: fs::DEFAULT_TMPFS_SIZE.to_string(),
+                ..fs::FsConfig::default()
+            },
+            namespaces: parse_isolate(ALL_NAMESPACES).expect("default namespaces are valid"),
+            time_offset: None,
+            freeze_on_stop: false,
+            max_startup_retries: 0,
+            sample_interval: None,
+            sample_output: None,
+            progress_watchdog: None,
+            labels: Vec::new(),
+            no_aslr: false,
+            umask: 0o022,
+            apparmor_profile: None,
+            preserve_fds: Vec::new(),
+            log_file: None,
+            events_socket: None,
+            cidfile: None,
+            force_cidfile: false,
+        }
+    }
+}
+
+/// Namespaces which can be selected with `--isolate`, by name
+const NAMESPACES: [(&str, CloneFlags); 8] = [
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("mount", CloneFlags::CLONE_NEWNS),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("user", CloneFlags::CLONE_NEWUSER),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("time", CLONE_NEWTIME),
+];
+
+/// Time namespace flag, missing from nix. Needs Linux 5.6
+pub const CLONE_NEWTIME: CloneFlags = CloneFlags::from_bits_retain(libc::CLONE_NEWTIME);
+
+/// Default for `--isolate`, every namespace the container can get except the time namespace,
+/// which only matters with `--time-offset`
+pub const ALL_NAMESPACES: &str = "pid,net,mount,uts,ipc,user,cgroup";
+
+/// Parses a comma separated list of namespaces to create for the container, e.g. "uts" or
+/// "pid,mount,user"
+pub fn parse_isolate(list: &str) -> anyhow::Result<CloneFlags> {
+    let mut namespaces = CloneFlags::empty();
+    for name in list.split(',') {
+        let (_, flag) = NAMESPACES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .with_context(|| {
+                let known: Vec<&str> = NAMESPACES.iter().map(|(known, _)| *known).collect();
+                format!(
+                    "unknown namespace '{}', use a list of {}",
+                    name,
+                    known.join(",")
+                )
+            })?;
+        namespaces |= *flag;
+    }
+    Ok(namespaces)
+}
+
+/// Flags passed to clone for the selected namespaces. A joined network namespace replaces
+/// the new one, and the cgroup namespace is unshared by the child once it has been moved
+/// into the container's cgroup, so the cgroup becomes the root of its view. The time
+/// namespace can't be created by clone at all, its flag overlaps the exit signal.
+fn clone_flags(namespaces: CloneFlags, joins_netns: bool) -> CloneFlags {
+    let mut flags = namespaces - CloneFlags::CLONE_NEWCGROUP - CLONE_NEWTIME;
+    if joins_netns {
+        flags.remove(CloneFlags::CLONE_NEWNET);
+    }
+    flags
+}
+
+/// Name of a namespace as used by `--isolate`
+fn namespace_name(namespace: CloneFlags) -> &'static str {
+    NAMESPACES
+        .iter()
+        .find(|(_, flag)| *flag == namespace)
+        .map_or("unknown", |(name, _)| name)
+}
+
+/// Checks that every requested feature can be set up with the namespaces the container
+/// gets. Without the mount namespace the command runs on the host's filesystem, so nothing
+/// may be mounted or written there.
+fn check_isolation(config: &ContainerConfig, uid: u32) -> anyhow::Result<()> {
+    if !config.isolates(CloneFlags::CLONE_NEWUSER) && uid != 0 {
+        anyhow::bail!("running without a user namespace requires root");
+    }
+    let has_sysctl = |prefix: &str| {
+        config
+            .sysctls
+            .iter()
+            .any(|(key, _)| key.starts_with(prefix))
+    };
+    let dns = &config.dns;
+    let fs = &config.fs;
+    let (mount, uts, network) = (
+        CloneFlags::CLONE_NEWNS,
+        CloneFlags::CLONE_NEWUTS,
+        CloneFlags::CLONE_NEWNET,
+    );
+    let requirements = [
+        (mount, "--volume, --tmpfs or --mount", !fs.mounts.is_empty()),
+        (mount, "--device", !fs.devices.is_empty()),
+        (mount, "--masked-path", !fs.masked_paths.is_empty()),
+        (mount, "--read-only-path", !fs.read_only_paths.is_empty()),
+        (mount, "--rootfs-size", fs.rootfs_size.is_some()),
+        (mount, "--persist", fs.persist),
+        (mount, "--preserve-on-failure", config.preserve_on_failure),
+        (mount, "--keep-upper-on-signal", config.keep_upper_on_signal),
+        (mount, "--network-alias", !config.network_aliases.is_empty()),
+        (
+            mount,
+            "DNS settings",
+            !dns.nameservers.is_empty() || !dns.search.is_empty() || !dns.options.is_empty(),
+        ),
+        (uts, "--hostname", config.hostname.is_some()),
+        (uts, "--domainname", config.domainname.is_some()),
+        (uts, "kernel.* sysctls", has_sysctl("kernel.")),
+        (
+            network,
+            "net.* sysctls",
+            has_sysctl("net.") && config.netns.is_none(),
+        ),
+        (network, "--gateway", config.gateway.is_some()),
+    ];
+    for (namespace, feature, requested) in requirements {
+        if requested && !config.isolates(namespace) {
+            let name = namespace_name(namespace);
+            anyhow::bail!(
+                "{} requires the {} namespace, add {} to --isolate",
+                feature,
+                name,
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Result of a finished container run
+pub struct RunOutcome {
+    pub id: String,
+    pub pid: i32,
+    pub exit_code: i32,
+    /// The container didn't stop in time and was killed
+    pub killed: bool,
+    /// The container ran longer than `--timeout` and was killed
+    pub timed_out: bool,
+    /// The runtime was asked to stop by SIGINT or SIGTERM while the container ran or was
+    /// torn down
+    pub interrupted: bool,
+    pub hostname: Option<String>,
+    pub cpu: Option<String>,
+    pub mem: Option<String>,
+    pub ip: Option<Ipv4Addr>,
+    pub labels: Vec<(String, String)>,
+}
+
+impl RunOutcome {
+    /// Serializes the outcome as a single line JSON object
+    pub fn to_json(&self) -> String {
+        let summary = RunSummary {
+            id: &self.id,
+            pid: self.pid,
+            status: if self.timed_out {
+                "timed_out"
+            } else if self.killed {
+                "killed"
+            } else {
+                "exited"
+            },
+            exit_code: self.exit_code,
+            hostname: self.hostname.as_deref(),
+            limits: LimitsSummary {
+                cpu: self.cpu.as_deref(),
+                mem: self.mem.as_deref(),
+            },
+            ip: self.ip,
+            labels: self
+                .labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+        };
+        serde_json::to_string(&summary).expect("run summary serializes to JSON")
+    }
+}
+
+/// JSON layout of a [RunOutcome]
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    id: &'a str,
+    pid: i32,
+    status: &'static str,
+    exit_code: i32,
+    hostname: Option<&'a str>,
+    limits: LimitsSummary<'a>,
+    ip: Option<Ipv4Addr>,
+    /// A label given more than once keeps its last value
+    labels: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Serialize)]
+struct LimitsSummary<'a> {
+    cpu: Option<&'a str>,
+    mem: Option<&'a str>,
+}
+
+/// Generates a random 16 hex digit container ID
+fn generate_container_id() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("failed to read /dev/urandom")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Writes the container ID to `path`. An existing file is only replaced with `force`,
+/// it may belong to a container which is still running.
+fn write_cidfile(path: &Path, id: &str, force: bool) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create cidfile {:?}", path))?;
+    file.write_all(id.as_bytes())
+        .with_context(|| format!("failed to write cidfile {:?}", path))?;
+    Ok(())
+}
+
+/// Parses a `key=value` label. Keys consist of letters, digits, '.', '-', '_' and '/',
+/// and start with a letter or a digit
+pub fn parse_label(label: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = label
+        .split_once('=')
+        .with_context(|| format!("invalid label '{}', expected key=value", label))?;
+    let valid_key = key.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/'));
+    if !valid_key {
+        anyhow::bail!("invalid label key '{}'", key);
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses a `key=value` sysctl setting. Only sysctls isolated by the container's namespaces
+/// are accepted: net.* from the network namespace and the UTS names.
+pub fn parse_sysctl(sysctl: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = sysctl
+        .split_once('=')
+        .with_context(|| format!("invalid sysctl '{}', expected key=value", sysctl))?;
+    let valid_key = !key.is_empty()
+        && key
+            .split('.')
+            .all(|part| !part.is_empty() && !part.contains('/'));
+    if !valid_key {
+        anyhow::bail!("invalid sysctl key '{}'", key);
+    }
+    let namespaced =
+        key.starts_with("net.") || matches!(key, "kernel.hostname" | "kernel.domainname");
+    if !namespaced {
+        anyhow::bail!(
+            "sysctl {} is not namespaced, setting it would change the host",
+            key
+        );
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Path of a sysctl in /proc/sys, e.g. net.core.somaxconn -> /proc/sys/net/core/somaxconn
+fn sysctl_path(key: &str) -> PathBuf {
+    Path::new("/proc/sys").join(key.replace('.', "/"))
+}
+
+fn child(
+    command: &str,
+    args: &[String],
+    config: &ContainerConfig,
+    is_parent_root: bool,
+    network_cidr: &Ipv4Cidr,
+) -> anyhow::Result<()> {
+    // the parent may have failed to write the mappings, don't run anything in a broken namespace
+    if config.isolates(CloneFlags::CLONE_NEWUSER) {
+        verify_id_maps()?;
+    }
+
+    // the parent has moved the child into the container's cgroup before releasing it
+    if config.isolates(CloneFlags::CLONE_NEWCGROUP) {
+        nix::sched::unshare(CloneFlags::CLONE_NEWCGROUP)
+            .context("failed to create cgroup namespace")?;
+    }
+
+    // only the command forked below enters the time namespace, so the offsets can still be
+    // written here, before any process runs in it
+    if config.isolates(CLONE_NEWTIME) {
+        nix::sched::unshare(CLONE_NEWTIME).context("failed to create time namespace")?;
+        if let Some(offset) = config.time_offset {
+            set_time_offset(offset)?;
+        }
+    }
+
+    // open the log file while the host filesystem is still visible
+    let log_file = match &config.log_file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {:?}", path))?,
+        ),
+        None => None,
+    };
+
+    // the host's /sys is not visible once the container filesystem is set up
+    let apparmor_profile = match &config.apparmor_profile {
+        Some(profile) if !apparmor_enabled() => {
+            eprintln!(
+                "warning: AppArmor is not enabled, ignoring profile {}",
+                profile
+            );
+            None
+        }
+        profile => profile.as_ref(),
+    };
+
+    // without the mount namespace the command uses the host's filesystem
+    let has_rootfs = config.isolates(CloneFlags::CLONE_NEWNS);
+    if has_rootfs {
+        if !is_parent_root {
+            fs::create_overlay_dirs(&fs::overlay_scratch("fs", &config.fs), config.fs.persist)?;
+        }
+        fs::create_container_filesystem("fs", &config.fs)?;
+    }
+
+    // a joined or the host's network namespace is configured by whoever created it
+    let has_veth = is_parent_root && config.creates_network();
+    if config.creates_network() {
+        net::bring_up_container_net(network_cidr, config.gateway, is_parent_root)?;
+    }
 
     if let Some(hostname) = &config.hostname {
         sethostname(hostname.as_str())?;
     }
 
+    if let Some(domainname) = &config.domainname {
+        set_domainname(domainname)?;
+    }
+
+    for (key, value) in &config.sysctls {
+        std::fs::write(sysctl_path(key), value)
+            .with_context(|| format!("failed to set sysctl {}={}", key, value))?;
+    }
+
+    if has_rootfs {
+        net::add_hosts_entry(
+            network_cidr,
+            has_veth,
+            &config.hostname,
+            &config.network_aliases,
+        )?;
+        net::write_resolv_conf(&config.dns)?;
+    }
+
     use nix::unistd::execve;
     use std::ffi::CString;
 
@@ -60,7 +561,7 @@ fn child(command: &str, args: &[String], config: &ContainerConfig) -> anyhow::Re
     // Build environment variables as CStrings: "KEY=VALUE"
     let mut c_env: Vec<CString> = Vec::new();
     for (key, value) in std::env::vars() {
-        let updated_value = if key == "PATH" {
+        let updated_value = if key == "PATH" && has_rootfs {
             // overwrite the PATH env variable to match alpine rootfs
             String::from("/bin:/sbin:/usr/bin:/usr/sbin")
         } else {
@@ -71,11 +572,31 @@ fn child(command: &str, args: &[String], config: &ContainerConfig) -> anyhow::Re
     }
 
     if config.drop_caps {
-        drop_caps()?;
+        let mut keep = config.cap_keep.clone();
+        if config.enable_fuse {
+            keep.push(Capability::CAP_SYS_ADMIN);
+        }
+        drop_caps(&keep)?;
     }
 
+    prepare_inherited_fds(&config.preserve_fds)?;
+
     match unsafe { fork() }.context("failed to fork")? {
         ForkResult::Child => {
+            if let Some(log_file) = &log_file {
+                redirect_output(log_file)?;
+            }
+            if let Some(profile) = &apparmor_profile {
+                apparmor_change_onexec(profile)?;
+            }
+            if config.no_aslr {
+                disable_aslr()?;
+            }
+            umask(Mode::from_bits_truncate(config.umask));
+            // the runtime blocks signals while supervising, the command starts without
+            SigSet::empty()
+                .thread_set_mask()
+                .context("failed to clear the signal mask")?;
             // execve replaces the current process, so this only returns on error
             execve(&cmd_cstring, &c_args, &c_env).context("failed to execute command")?;
 
@@ -83,16 +604,159 @@ fn child(command: &str, args: &[String], config: &ContainerConfig) -> anyhow::Re
             unreachable!()
         }
         ForkResult::Parent { child } => {
-            run_init(child)?;
+            run_init(child, config.stop_signal)?;
         }
     }
 
     Ok(())
 }
 
-fn drop_caps() -> anyhow::Result<()> {
+/// Parses an octal file mode creation mask, e.g. "022" or "0027"
+pub fn parse_umask(value: &str) -> anyhow::Result<libc::mode_t> {
+    let mask = libc::mode_t::from_str_radix(value, 8)
+        .with_context(|| format!("'{}' is not an octal number", value))?;
+    if mask > 0o777 {
+        anyhow::bail!("umask '{}' is out of range 000-777", value);
+    }
+    Ok(mask)
+}
+
+/// Checks that an id map read from /proc maps root in the namespace to some id outside
+fn check_id_map(name: &str, map: &str) -> anyhow::Result<()> {
+    let maps_root = map
+        .lines()
+        .any(|line| line.split_whitespace().next() == Some("0"));
+    if !maps_root {
+        anyhow::bail!(
+            "{} does not map root in the user namespace: {:?}",
+            name,
+            map
+        );
+    }
+    Ok(())
+}
+
+/// Reads back the uid and gid maps of the current process and verifies they were written
+fn verify_id_maps() -> anyhow::Result<()> {
+    for name in ["uid_map", "gid_map"] {
+        let path = format!("/proc/self/{}", name);
+        let map =
+            std::fs::read_to_string(&path).with_context(|| format!("failed to read {}", path))?;
+        check_id_map(name, &map)?;
+    }
+    Ok(())
+}
+
+/// Turns off address space layout randomization for programs executed by this process
+fn disable_aslr() -> anyhow::Result<()> {
+    // 0xffffffff queries the current persona without changing it
+    let persona = unsafe { libc::personality(0xffffffff) };
+    nix::errno::Errno::result(persona).context("failed to get personality")?;
+    let res = unsafe { libc::personality((persona | libc::ADDR_NO_RANDOMIZE) as libc::c_ulong) };
+    nix::errno::Errno::result(res).context("failed to disable ASLR")?;
+    Ok(())
+}
+
+/// Checks whether AppArmor is enabled on the host, needs the host's /sys
+fn apparmor_enabled() -> bool {
+    std::fs::read_to_string("/sys/module/apparmor/parameters/enabled")
+        .is_ok_and(|enabled| enabled.trim() == "Y")
+}
+
+/// Makes the kernel switch to the AppArmor `profile` on the next execve, like
+/// `aa_change_onexec` does
+fn apparmor_change_onexec(profile: &str) -> anyhow::Result<()> {
+    // newer kernels have a per-LSM attribute directory
+    let attr = if Path::new("/proc/self/attr/apparmor/exec").exists() {
+        "/proc/self/attr/apparmor/exec"
+    } else {
+        "/proc/self/attr/exec"
+    };
+    std::fs::write(attr, format!("exec {}", profile))
+        .with_context(|| format!("failed to set AppArmor profile {}", profile))?;
+    Ok(())
+}
+
+/// Redirects stdout and stderr of the current process into `file`
+fn redirect_output(file: &File) -> anyhow::Result<()> {
+    for target in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        let res = unsafe { libc::dup2(file.as_raw_fd(), target) };
+        nix::errno::Errno::result(res).context("failed to redirect output to log file")?;
+    }
+    Ok(())
+}
+
+/// Marks every file descriptor except stdio and `preserve_fds` close-on-exec, and clears
+/// close-on-exec on `preserve_fds` so they are passed to the command.
+fn prepare_inherited_fds(preserve_fds: &[RawFd]) -> anyhow::Result<()> {
+    for &fd in preserve_fds {
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))
+            .with_context(|| format!("failed to preserve fd {}", fd.as_raw_fd()))?;
+    }
+
+    let open_fds: Vec<RawFd> = std::fs::read_dir("/proc/self/fd")
+        .context("failed to list open fds")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    for fd in open_fds {
+        if fd <= 2 || preserve_fds.contains(&fd) {
+            continue;
+        }
+        // the fd used to read /proc/self/fd is already closed, ignore failures
+        let _ = fcntl(
+            unsafe { BorrowedFd::borrow_raw(fd) },
+            FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC),
+        );
+    }
+    Ok(())
+}
+
+fn set_domainname(name: &str) -> anyhow::Result<()> {
+    let res = unsafe { libc::setdomainname(name.as_ptr() as *const libc::c_char, name.len()) };
+    nix::errno::Errno::result(res).context("failed to set domainname")?;
+    Ok(())
+}
+
+/// Validates a host or domain name: dot-separated labels of up to 63 letters, digits
+/// and hyphens, not starting or ending with a hyphen, 64 characters at most in total
+pub fn validate_hostname(name: &str) -> anyhow::Result<String> {
+    if name.is_empty() || name.len() > 64 {
+        anyhow::bail!("'{}' must be between 1 and 64 characters long", name);
+    }
+    for label in name.split('.') {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid {
+            anyhow::bail!(
+                "'{}' is not a valid name, label '{}' is invalid",
+                name,
+                label
+            );
+        }
+    }
+    Ok(name.to_string())
+}
+
+/// Reads the hostname from the first line of a file and validates it
+pub fn read_hostname_file(path: &Path) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read hostname file {:?}", path))?;
+    let hostname = content.lines().next().unwrap_or_default().trim();
+    validate_hostname(hostname).with_context(|| format!("invalid hostname in {:?}", path))
+}
+
+/// Drops all bounding capabilities except CAP_CHOWN and `keep`. The command and anything it
+/// executes, setuid-root binaries included, can't get a dropped capability back.
+fn drop_caps(keep: &[Capability]) -> anyhow::Result<()> {
     let mut caps_drop = caps::all();
     caps_drop.remove(&Capability::CAP_CHOWN);
+    for cap in keep {
+        caps_drop.remove(cap);
+    }
 
     for cap in caps_drop {
         caps::drop(None, CapSet::Bounding, cap)
@@ -114,7 +778,8 @@ fn reap_zombies(child: Pid) {
                 println!("child received signal {}", sig);
                 std::process::exit(128 + sig as i32);
             }
-            Ok(_) => break,
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(_) => continue,
             Err(nix::errno::Errno::ECHILD) => break,
             Err(err) => {
                 eprintln!("waitpid error: {}", err);
@@ -124,7 +789,34 @@ fn reap_zombies(child: Pid) {
     }
 }
 
-fn run_init(child: Pid) -> anyhow::Result<()> {
+/// Parses a capability name, with or without the CAP_ prefix, e.g. "NET_ADMIN" or "cap_kill"
+pub fn parse_capability(value: &str) -> anyhow::Result<Capability> {
+    let name = value.to_ascii_uppercase();
+    let name = if name.starts_with("CAP_") {
+        name
+    } else {
+        format!("CAP_{}", name)
+    };
+    name.parse()
+        .map_err(|_| anyhow::anyhow!("unknown capability {}", value))
+}
+
+/// Parses a signal given either by name ("SIGINT", "INT") or by number ("2")
+pub fn parse_signal(value: &str) -> anyhow::Result<Signal> {
+    if let Ok(num) = value.parse::<i32>() {
+        return Signal::try_from(num).with_context(|| format!("invalid signal number {}", num));
+    }
+    let name = value.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    name.parse::<Signal>()
+        .with_context(|| format!("unknown signal '{}'", value))
+}
+
+fn run_init(child: Pid, stop_signal: Signal) -> anyhow::Result<()> {
     let _ = nix::sys::prctl::set_child_subreaper(true);
     let mut signal_mask = SigSet::empty();
     signal_mask.add(Signal::SIGTERM);
@@ -149,6 +841,11 @@ fn run_init(child: Pid) -> anyhow::Result<()> {
             Signal::SIGCHLD => {
                 reap_zombies(child);
             }
+            // SIGTERM is the generic "please stop" request, translate it into the
+            // signal the contained command expects
+            Signal::SIGTERM => {
+                kill(child, stop_signal)?;
+            }
             _ => {
                 kill(child, signal)?;
             }
@@ -156,119 +853,778 @@ fn run_init(child: Pid) -> anyhow::Result<()> {
     }
 }
 
-pub fn run_in_container(
-    command: &str,
-    args: &[String],
-    cpu: &Option<String>,
-    mem: &Option<String>,
-    hostname: &Option<String>,
-    drop_caps: bool,
-) -> anyhow::Result<()> {
-    // clone flags
-    let clone_flags = CloneFlags::CLONE_NEWPID
-        | CloneFlags::CLONE_NEWUSER
-        | CloneFlags::CLONE_NEWNS
-        | CloneFlags::CLONE_NEWNET
-        | CloneFlags::CLONE_NEWUTS;
-    // allocate stack for the child process
-    let mut stack = vec![0u8; STACK_SIZE];
-
+/// Runs `command` with `args` in a new container and waits for it to exit. This is the entry
+/// point for embedding the runtime: it sets up the cgroup, network and filesystem described
+/// by `config`, and tears all of them down again before returning, also when supervising the
+/// container fails or panics.
+///
+/// While the container runs and is torn down, SIGINT and SIGTERM are blocked in the calling
+/// thread and handled as requests to stop the container, reported as
+/// [RunOutcome::interrupted]. The thread's signal mask is restored before returning.
+///
+/// # Example
+/// Running as root, from a directory holding the container filesystem in fs/
+/// ```no_run
+/// use container::{ContainerConfig, run};
+///
+/// let config = ContainerConfig {
+///     hostname: Some("sandbox".to_string()),
+///     ..ContainerConfig::default()
+/// };
+/// let outcome = run("/bin/sh", &["-c".into(), "exit 3".into()], &config)?;
+/// assert_eq!(outcome.exit_code, 3);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn run(command: &str, args: &[String], config: &ContainerConfig) -> anyhow::Result<RunOutcome> {
     let container_net_cidr =
         Ipv4Cidr::new(Ipv4Addr::new(192, 168, 200, 0), 24).context("invalid CIDR")?;
 
     let uid = unsafe { geteuid() };
-    let gid = unsafe { getegid() };
+    check_isolation(config, uid)?;
+    if let Some(gateway) = &config.gateway {
+        if uid != 0 {
+            eprintln!(
+                "warning: the container has no network interface without root, ignoring --gateway"
+            );
+        }
+        net::validate_gateway(&container_net_cidr, gateway)?;
+    }
 
-    let (read_fd, write_fd) = pipe()?;
+    if config.netns.is_some() && uid != 0 {
+        anyhow::bail!("joining a network namespace requires root");
+    }
 
-    // convert to raw FD - I can't figure out how to trick borrow checked into allowing copying OwnedFd into the child
-    let child_read_fd = read_fd.as_raw_fd();
-    let child_write_fd = write_fd.as_raw_fd();
+    let id = generate_container_id()?;
+    if let Some(cidfile) = &config.cidfile {
+        write_cidfile(cidfile, &id, config.force_cidfile)?;
+    }
 
-    let child_pid = unsafe {
-        clone(
-            Box::new(move || {
-                // restore OwnedFd from raw FD
-                let read_fd = OwnedFd::from_raw_fd(child_read_fd);
-                let write_fd = OwnedFd::from_raw_fd(child_write_fd);
-
-                // close writing part - we don't need it
-                if let Err(e) = close(write_fd) {
-                    eprint!("failed to close pipe {}", e);
-                    return 1;
-                }
+    // the scratch filesystem has to be mounted before the container's mount namespace
+    // is cloned, so the container sees it
+    if let Some(size) = config.fs.rootfs_size {
+        if uid != 0 {
+            anyhow::bail!("limiting the root filesystem size requires root");
+        }
+        fs::mount_scratch("fs", size)?;
+    }
+
+    // the cgroup is removed by the teardown, or dropped automatically if
+    // run fails before that
+    let (child_pid, cgroup) = retry(
+        config.max_startup_retries.saturating_add(1),
+        STARTUP_RETRY_DELAY,
+        is_transient,
+        || start_container(command, args, config, &id, &container_net_cidr),
+    )
+    .inspect_err(|_| {
+        if config.fs.rootfs_size.is_some() {
+            let _ = fs::unmount_scratch("fs");
+        }
+    })
+    .context("failed to start container")?;
+
+    println!("started child with PID={}", child_pid);
+
+    let mut resources = ContainerResources {
+        unreaped_child: Some(child_pid),
+        cgroup,
+        bridge: (uid == 0 && config.creates_network()).then(|| config.bridge.clone()),
+        overlay_dir: fs::overlay_scratch("fs", &config.fs),
+        scratch_mounted: config.fs.rootfs_size.is_some(),
+        preserve_dir: None,
+        persist: config.fs.persist,
+    };
+    // stop requests stay blocked until the container is torn down, one arriving during the
+    // teardown would otherwise kill the runtime and leak what is left of the container
+    let ((supervised, teardown), late_stop_request) = with_stop_signals_blocked(|stop_signals| {
+        // whatever goes wrong while the container runs, it is still torn down
+        let supervised = panic::catch_unwind(AssertUnwindSafe(|| {
+            supervise(child_pid, resources.cgroup.as_ref(), stop_signals, config)
+        }));
+        if let Ok(Ok(termination)) = &supervised {
+            resources.unreaped_child = None;
+            resources.preserve_dir = termination.preserve_dir(
+                &id,
+                config.preserve_on_failure,
+                config.keep_upper_on_signal,
+            );
+        }
+        (supervised, resources.teardown())
+    });
+
+    // like OCI poststop hooks, a failure is only reported
+    if let Some(hook) = &config.poststop_hook
+        && let Err(e) = hooks::run_hook("poststop", hook, &id, child_pid)
+    {
+        eprintln!("warning: {:#}", e);
+    }
+    let termination = match supervised {
+        Ok(result) => result?,
+        Err(panic) => panic::resume_unwind(panic),
+    };
+    teardown?;
+
+    Ok(RunOutcome {
+        id,
+        pid: child_pid.as_raw(),
+        exit_code: termination.exit_code,
+        killed: termination.killed,
+        timed_out: termination.timed_out,
+        interrupted: termination.interrupted || late_stop_request,
+        hostname: config.hostname.clone(),
+        cpu: config.limits.cpu.clone(),
+        mem: config.limits.mem.clone(),
+        ip: if uid == 0 && config.creates_network() {
+            Some(net::container_ip(&container_net_cidr)?)
+        } else {
+            None
+        },
+        labels: config.labels.clone(),
+    })
+}
+
+/// How a supervised container ended
+struct Termination {
+    exit_code: i32,
+    /// The container had to be killed after a stop request
+    killed: bool,
+    /// The container was killed for running longer than its timeout
+    timed_out: bool,
+    /// The runtime was asked to stop the container by SIGINT or SIGTERM
+    interrupted: bool,
+}
+
+impl Termination {
+    /// Where the overlay directories of the run with `id` are moved to instead of being
+    /// removed, if it was interrupted or failed and the user asked to keep them
+    fn preserve_dir(
+        &self,
+        id: &str,
+        preserve_on_failure: bool,
+        keep_upper_on_signal: bool,
+    ) -> Option<PathBuf> {
+        let dir = if keep_upper_on_signal && self.interrupted {
+            INTERRUPTED_RUNS_DIR
+        } else if preserve_on_failure && self.exit_code != 0 {
+            FAILED_RUNS_DIR
+        } else {
+            return None;
+        };
+        Some(Path::new("fs").join(dir).join(id))
+    }
+}
+
+/// Waits for the container to exit while sampling its resource usage, enforcing the timeout
+/// and handling stop requests, and reports its start, OOM kills and exit as events.
+/// `stop_signals` have to be blocked in the calling thread, see [with_stop_signals_blocked].
+fn supervise(
+    child_pid: Pid,
+    cgroup: Option<&Cgroup>,
+    stop_signals: &SigSet,
+    config: &ContainerConfig,
+) -> anyhow::Result<Termination> {
+    let mut events = EventSink::connect(config.events_socket.as_deref());
+    events.send(&Event::Start {
+        pid: child_pid.as_raw(),
+    });
+
+    let mut sample_output: Option<Box<dyn Write + Send>> = match config.sample_interval {
+        Some(_) if cgroup.is_none() => {
+            eprintln!("warning: resource sampling requires a cgroup, run as root");
+            None
+        }
+        Some(_) => match &config.sample_output {
+            Some(path) => {
+                Some(Box::new(File::create(path).with_context(|| {
+                    format!("failed to create sample output {:?}", path)
+                })?))
+            }
+            None => Some(Box::new(std::io::stdout())),
+        },
+        None => None,
+    };
 
-                // wait for the parent
-                let mut buf = [0u8];
-                if let Err(e) = read(read_fd, &mut buf) {
-                    eprint!("failed to sync with parent {}", e);
-                    return 1;
+    let init = pidfd_open(child_pid)?;
+    let exited = AtomicBool::new(false);
+    let timed_out = AtomicBool::new(false);
+    let interrupted = AtomicBool::new(false);
+    let (exit_code, killed) = thread::scope(|scope| {
+        if let (Some(cgroup), Some(interval), Some(output)) =
+            (cgroup, config.sample_interval, &mut sample_output)
+        {
+            let exited = &exited;
+            scope.spawn(move || {
+                if let Err(e) = sampler::sample(cgroup, interval, output, exited) {
+                    eprintln!("resource sampling stopped: {:#}", e);
                 }
+            });
+        }
+        match (cgroup, config.progress_watchdog) {
+            (Some(cgroup), Some(stall_after)) => {
+                let exited = &exited;
+                scope.spawn(move || {
+                    if let Err(e) = sampler::watch_progress(cgroup, stall_after, exited) {
+                        eprintln!("progress watchdog stopped: {:#}", e);
+                    }
+                });
+            }
+            (None, Some(_)) => {
+                eprintln!("warning: the progress watchdog requires a cgroup, run as root")
+            }
+            _ => {}
+        }
+        if let Some(timeout) = config.timeout {
+            let (init, exited, timed_out) = (&init, &exited, &timed_out);
+            scope.spawn(move || watchdog(init, cgroup, timeout, exited, timed_out));
+        }
+        let stop = StopContext {
+            init: &init,
+            cgroup,
+            stop_signal: config.stop_signal,
+            freeze_on_stop: config.freeze_on_stop,
+            stop_timeout: config.stop_timeout,
+            signals: stop_signals,
+            exited: &exited,
+            interrupted: &interrupted,
+        };
+        let stopper = scope.spawn(move || handle_stop_requests(&stop));
 
-                let config = ContainerConfig {
-                    is_parent_root: uid == 0,
-                    network_cidr: container_net_cidr,
-                    hostname: hostname.clone(),
-                    drop_caps,
-                };
-                // This runs in the child process with PID 1 in the new namespace
-                if let Err(e) = child(command, args, &config) {
-                    eprintln!("child process failed: {:#}", e);
-                    return 1;
-                };
-                return 0;
-            }),
-            &mut stack,
-            clone_flags,
-            Some(Signal::SIGCHLD as i32),
+        let exit_code = wait_for_child(child_pid).unwrap_or(1);
+        exited.store(true, Ordering::Relaxed);
+        (exit_code, stopper.join().unwrap_or(false))
+    });
+
+    if let Some(cgroup) = cgroup
+        && let Ok(oom_kills) = cgroup.oom_kill_count()
+        && oom_kills > 0
+    {
+        events.send(&Event::Oom {
+            pid: child_pid.as_raw(),
+            oom_kills,
+        });
+    }
+    events.send(&Event::Exit {
+        pid: child_pid.as_raw(),
+        exit_code,
+    });
+
+    Ok(Termination {
+        exit_code,
+        killed,
+        timed_out: timed_out.load(Ordering::Relaxed),
+        interrupted: interrupted.load(Ordering::Relaxed),
+    })
+}
+
+/// Runs `f` with SIGINT and SIGTERM blocked in the calling thread, so they can be handled
+/// as stop requests, see [handle_stop_requests]. `f` gets the blocked signals. The previous
+/// signal mask is restored afterwards, also when `f` panics, otherwise the next container
+/// cloned from the thread, and its command, would start with the signals blocked.
+///
+/// # Returns
+/// The result of `f` and whether a stop request arrived which `f` left unhandled
+fn with_stop_signals_blocked<T>(f: impl FnOnce(&SigSet) -> T) -> (T, bool) {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    let blocked = BlockedSignals::block(signals);
+    let result = f(&blocked.signals);
+    (result, blocked.take_pending())
+}
+
+/// Signals blocked in the calling thread, which gets its previous signal mask back on drop
+struct BlockedSignals {
+    signals: SigSet,
+    previous: SigSet,
+}
+
+impl BlockedSignals {
+    fn block(signals: SigSet) -> Self {
+        // only fails for an invalid operation
+        let previous = signals
+            .thread_swap_mask(SigmaskHow::SIG_BLOCK)
+            .expect("failed to block stop signals");
+        BlockedSignals { signals, previous }
+    }
+
+    /// Accepts the pending signals, which would take their default action once unblocked.
+    /// Returns whether there were any.
+    fn take_pending(&self) -> bool {
+        let poll = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let mut pending = false;
+        while unsafe { libc::sigtimedwait(self.signals.as_ref(), std::ptr::null_mut(), &poll) } > 0
+        {
+            pending = true;
+        }
+        pending
+    }
+}
+
+impl Drop for BlockedSignals {
+    fn drop(&mut self) {
+        self.take_pending();
+        let _ = self.previous.thread_set_mask();
+    }
+}
+
+/// The running container and the stop settings [handle_stop_requests] works with
+struct StopContext<'a> {
+    /// pidfd of the container's init
+    init: &'a OwnedFd,
+    cgroup: Option<&'a Cgroup>,
+    stop_signal: Signal,
+    freeze_on_stop: bool,
+    stop_timeout: Duration,
+    /// Signals which request a stop, blocked in all threads
+    signals: &'a SigSet,
+    /// Set once the container has exited
+    exited: &'a AtomicBool,
+    /// Set on the first stop request
+    interrupted: &'a AtomicBool,
+}
+
+/// Stops the container when the runtime receives one of `signals`, until `exited` is set.
+///
+/// The first request is delivered to the container's `init` as SIGTERM, which passes the stop
+/// signal on to the command. With `freeze_on_stop` the cgroup is frozen instead and the stop
+/// signal is delivered to every process in it at once. The container is killed if it is still
+/// running `stop_timeout` after the first request, or when the request is repeated.
+///
+/// # Returns
+/// Whether the container had to be killed
+fn handle_stop_requests(stop: &StopContext) -> bool {
+    let StopContext {
+        init,
+        cgroup,
+        stop_signal,
+        freeze_on_stop,
+        stop_timeout,
+        signals,
+        exited,
+        interrupted,
+    } = *stop;
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 100_000_000,
+    };
+    let mut requests = 0;
+    let mut deadline = None;
+    let mut killed = false;
+    while !exited.load(Ordering::Relaxed) {
+        let signo = unsafe { libc::sigtimedwait(signals.as_ref(), std::ptr::null_mut(), &timeout) };
+        let grace_expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if signo < 0 && (!grace_expired || killed) {
+            continue;
+        }
+        requests += 1;
+        interrupted.store(true, Ordering::Relaxed);
+        let result = match (requests, cgroup) {
+            (1, Some(cgroup)) if freeze_on_stop => cgroup.signal_all(stop_signal),
+            (1, _) => {
+                pidfd_send_signal(init, Signal::SIGTERM).context("failed to signal container")
+            }
+            // killing init takes down the whole PID namespace, the cgroup also catches
+            // processes which were moved into it from outside
+            (_, cgroup) => pidfd_send_signal(init, Signal::SIGKILL)
+                .context("failed to kill container")
+                .and(cgroup.map_or(Ok(()), Cgroup::kill_all)),
+        };
+        if requests == 1 {
+            deadline = Some(Instant::now() + stop_timeout);
+        } else {
+            if grace_expired {
+                eprintln!("container did not stop in {:?}, killing it", stop_timeout);
+            }
+            killed = true;
+        }
+        if let Err(e) = result {
+            eprintln!("failed to stop container: {:#}", e);
+        }
+    }
+    killed
+}
+
+/// Kills the container if it is still running `timeout` after start. `timed_out` is set
+/// before the kill, so the outcome can tell a timeout from the command being killed by a signal.
+fn watchdog(
+    init: &OwnedFd,
+    cgroup: Option<&Cgroup>,
+    timeout: Duration,
+    exited: &AtomicBool,
+    timed_out: &AtomicBool,
+) {
+    let deadline = Instant::now() + timeout;
+    while !exited.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            eprintln!("container ran longer than {:?}, killing it", timeout);
+            timed_out.store(true, Ordering::Relaxed);
+            let result = pidfd_send_signal(init, Signal::SIGKILL)
+                .context("failed to kill container")
+                .and(cgroup.map_or(Ok(()), Cgroup::kill_all));
+            if let Err(e) = result {
+                eprintln!("failed to stop container: {:#}", e);
+            }
+            return;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+}
+
+/// Opens a pidfd referring to `pid`, which keeps referring to the same process even if the
+/// pid gets reused
+fn pidfd_open(pid: Pid) -> anyhow::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    let fd = nix::errno::Errno::result(fd).context("pidfd_open failed")?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Sends `signal` to the process referred to by `pidfd`
+fn pidfd_send_signal(pidfd: &OwnedFd, signal: Signal) -> anyhow::Result<()> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal as i32,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
         )
+    };
+    nix::errno::Errno::result(res).with_context(|| format!("failed to send {}", signal))?;
+    Ok(())
+}
+
+/// Steps of tearing down a container, in the order [teardown] runs them.
+///
+/// Processes go first: a cgroup can only be removed once it is empty, and the overlay is
+/// unmounted by the kernel when the last process in the container's mount namespace exits.
+/// The network is removed after the cgroup, when nothing can send packets to the
+/// container anymore, and the overlay directories last, once nothing uses them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TeardownStep {
+    Processes,
+    Cgroup,
+    Network,
+    Overlay,
+}
+
+impl TeardownStep {
+    const ORDER: [TeardownStep; 4] = [
+        TeardownStep::Processes,
+        TeardownStep::Cgroup,
+        TeardownStep::Network,
+        TeardownStep::Overlay,
+    ];
+}
+
+/// Runs every teardown step in [TeardownStep::ORDER]. A failed step doesn't stop the
+/// following ones, the first error is returned and later ones are reported.
+fn teardown(mut run_step: impl FnMut(TeardownStep) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let mut result = Ok(());
+    for step in TeardownStep::ORDER {
+        if let Err(e) = run_step(step).with_context(|| format!("teardown of {:?} failed", step)) {
+            if result.is_ok() {
+                result = Err(e);
+            } else {
+                eprintln!("{:#}", e);
+            }
+        }
     }
-    .context("Failed to clone process")?;
+    result
+}
+
+/// Host resources held by a container
+struct ContainerResources {
+    /// Container init which has not been waited for yet
+    unreaped_child: Option<Pid>,
+    cgroup: Option<Cgroup>,
+    /// Bridge of the host side of the network, only set up when running as root
+    bridge: Option<String>,
+    /// Directory holding the overlay upper and work directories
+    overlay_dir: String,
+    /// Whether the size-limited scratch filesystem is mounted
+    scratch_mounted: bool,
+    /// Where the overlay directories of a failed run are moved to instead of being removed
+    preserve_dir: Option<PathBuf>,
+    /// Keep the upper layer in place when preserving, it holds the persisted state
+    persist: bool,
+}
+
+impl ContainerResources {
+    /// Releases the resources in the order defined by [TeardownStep]
+    fn teardown(mut self) -> anyhow::Result<()> {
+        teardown(|step| match step {
+            TeardownStep::Processes => {
+                if let Some(child) = self.unreaped_child.take() {
+                    let _ = kill(child, Signal::SIGKILL);
+                    let _ = waitpid(child, None);
+                }
+                if let Some(cgroup) = &self.cgroup {
+                    cgroup.kill_remaining();
+                }
+                Ok(())
+            }
+            TeardownStep::Cgroup => {
+                drop(self.cgroup.take());
+                Ok(())
+            }
+            TeardownStep::Network => match &self.bridge {
+                Some(bridge) => net::cleanup_network(bridge),
+                None => Ok(()),
+            },
+            TeardownStep::Overlay => {
+                if let Some(dest) = &self.preserve_dir {
+                    fs::preserve_overlay_dirs(&self.overlay_dir, dest, !self.persist)?;
+                    println!("the container's overlay directories are kept in {:?}", dest);
+                }
+                fs::remove_overlay_dirs(&self.overlay_dir)?;
+                if self.scratch_mounted {
+                    fs::unmount_scratch("fs")?;
+                }
+                Ok(())
+            }
+        })
+    }
+}
+
+/// Clones the container process, sets it up from the host side and runs the prestart hook.
+/// If the setup or the hook fails,
+/// the child is killed and the partially created resources are torn down, so the launch
+/// can be retried.
+fn start_container<'a>(
+    command: &'a str,
+    args: &'a [String],
+    config: &'a ContainerConfig,
+    id: &str,
+    container_net_cidr: &'a Ipv4Cidr,
+) -> anyhow::Result<(Pid, Option<Cgroup>)> {
+    let clone_flags = clone_flags(config.namespaces, config.netns.is_some());
+    // allocate stack for the child process
+    let mut stack = vec![0u8; STACK_SIZE];
+
+    let uid = unsafe { geteuid() };
+
+    // close-on-exec keeps the sync pipe from leaking into the ip commands run
+    // during network setup and into the contained command
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+
+    // convert to raw FD - I can't figure out how to trick borrow checked into allowing copying OwnedFd into the child
+    let child_read_fd = read_fd.as_raw_fd();
+    let child_write_fd = write_fd.as_raw_fd();
+
+    let mut clone_child = || {
+        unsafe {
+            clone(
+                Box::new(move || {
+                    // restore OwnedFd from raw FD
+                    let read_fd = OwnedFd::from_raw_fd(child_read_fd);
+                    let write_fd = OwnedFd::from_raw_fd(child_write_fd);
+
+                    // close writing part - we don't need it
+                    if let Err(e) = close(write_fd) {
+                        eprint!("failed to close pipe {}", e);
+                        return 1;
+                    }
+
+                    // wait for the parent
+                    let mut buf = [0u8];
+                    if let Err(e) = read(read_fd, &mut buf) {
+                        eprint!("failed to sync with parent {}", e);
+                        return 1;
+                    }
+
+                    // This runs in the child process with PID 1 in the new namespace
+                    if let Err(e) = child(command, args, config, uid == 0, container_net_cidr) {
+                        eprintln!("child process failed: {:#}", e);
+                        return 1;
+                    };
+                    0
+                }),
+                &mut stack,
+                clone_flags,
+                Some(Signal::SIGCHLD as i32),
+            )
+        }
+        .map_err(clone_error)
+    };
+    // the child inherits the network namespace of the thread cloning it
+    let child_pid = match &config.netns {
+        Some(netns) => net::in_netns(netns, clone_child)?,
+        None => clone_child()?,
+    };
 
     close(read_fd)?;
 
-    write_proc_file(child_pid, "uid_map", &format!("0 {} 1\n", uid))?;
-    write_proc_file(child_pid, "setgroups", "deny\n")?;
-    write_proc_file(child_pid, "gid_map", &format!("0 {} 1\n", gid))?;
+    // the prestart hook sees the container's namespaces before the command starts
+    let setup = setup_host(child_pid, config, container_net_cidr).and_then(|cgroup| {
+        if let Some(hook) = &config.prestart_hook {
+            hooks::run_hook("prestart", hook, id, child_pid)?;
+        }
+        release_child(child_pid, &write_fd)?;
+        Ok(cgroup)
+    });
+    match setup {
+        Ok(cgroup) => {
+            close(write_fd)?;
+            Ok((child_pid, cgroup))
+        }
+        Err(e) => {
+            // a setup failure is usually caused by the child dying before the handshake
+            let e = match peek_exit_status(child_pid, false) {
+                Some(status) => e.context(initialization_failure(status)),
+                None => e,
+            };
+            // the scratch filesystem is reused by the next attempt
+            let resources = ContainerResources {
+                unreaped_child: Some(child_pid),
+                cgroup: None,
+                bridge: (uid == 0 && config.creates_network()).then(|| config.bridge.clone()),
+                overlay_dir: fs::overlay_scratch("fs", &config.fs),
+                scratch_mounted: false,
+                preserve_dir: None,
+                persist: config.fs.persist,
+            };
+            // the setup may have failed before creating everything there is to remove
+            let _ = resources.teardown();
+            Err(e)
+        }
+    }
+}
 
-    // keep variable here, so if we use cgroup, it will be dropped automatically
-    // when run_in_container finishes
-    let mut _cgroup: Option<Cgroup> = None;
+/// Explains why clone failed. EAGAIN means a process limit was hit, which the generic
+/// message doesn't make obvious.
+fn clone_error(errno: nix::errno::Errno) -> anyhow::Error {
+    match errno {
+        // the errno stays in the chain, so the launch can be retried
+        nix::errno::Errno::EAGAIN => anyhow::Error::new(errno).context(
+            "Failed to clone process: too many processes. The user's process limit \
+             (RLIMIT_NPROC, ulimit -u), pids.max of the runtime's cgroup or the system wide \
+             kernel.threads-max was reached. Stop other containers or raise the limit",
+        ),
+        errno => anyhow::Error::new(errno).context("Failed to clone process"),
+    }
+}
 
-    if uid == 0 {
-        fs::create_overlay_dirs("fs")?;
+/// Lets the child waiting on the sync pipe go on with the container setup. Fails with the
+/// child's exit status if it died before reading the sync byte.
+fn release_child(child_pid: Pid, write_fd: &OwnedFd) -> anyhow::Result<()> {
+    match write(write_fd, b"1") {
+        Ok(_) => Ok(()),
+        // every read end is closed, the child is exiting if it hasn't yet
+        Err(nix::errno::Errno::EPIPE) => match peek_exit_status(child_pid, true) {
+            Some(status) => Err(anyhow::anyhow!(initialization_failure(status))),
+            None => anyhow::bail!("container closed the sync pipe during initialization"),
+        },
+        Err(e) => Err(e).context("failed to sync with the container"),
+    }
+}
 
-        net::setup_network_host(&container_net_cidr)?;
-        net::move_into_container(child_pid)?;
+/// Returns how the child exited, leaving it unreaped for the teardown. Only waits for the
+/// child to exit with `block`.
+fn peek_exit_status(pid: Pid, block: bool) -> Option<WaitStatus> {
+    let mut flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT;
+    if !block {
+        flags |= WaitPidFlag::WNOHANG;
+    }
+    match waitid(Id::Pid(pid), flags) {
+        Ok(status @ (WaitStatus::Exited(..) | WaitStatus::Signaled(..))) => Some(status),
+        _ => None,
+    }
+}
 
-        let cg = Cgroup::new(cpu, mem)?;
-        cg.add_process(child_pid.as_raw())?;
-        _cgroup = Some(cg);
+/// Describes a child which exited before the container was set up
+fn initialization_failure(status: WaitStatus) -> String {
+    match status {
+        WaitStatus::Exited(_, code) => {
+            format!("container failed during initialization, exit code {}", code)
+        }
+        WaitStatus::Signaled(_, signal, _) => {
+            format!(
+                "container failed during initialization, killed by {}",
+                signal
+            )
+        }
+        _ => String::from("container failed during initialization"),
     }
+}
 
-    write(&write_fd, b"1")?;
-    close(write_fd)?;
+/// Writes the user namespace mappings and, when running as root, sets up the overlay
+/// directories, the network and the cgroup for the child
+fn setup_host(
+    child_pid: Pid,
+    config: &ContainerConfig,
+    container_net_cidr: &Ipv4Cidr,
+) -> anyhow::Result<Option<Cgroup>> {
+    let uid = unsafe { geteuid() };
+    let gid = unsafe { getegid() };
 
-    println!("started child with PID={}", child_pid);
-    let _ = wait_for_child(child_pid);
+    if config.isolates(CloneFlags::CLONE_NEWUSER) {
+        write_proc_file(child_pid, "uid_map", &format!("0 {} 1\n", uid))?;
+        write_proc_file(child_pid, "setgroups", "deny\n")?;
+        write_proc_file(child_pid, "gid_map", &format!("0 {} 1\n", gid))?;
+    }
 
-    if uid == 0 {
-        net::cleanup_network()?;
+    if let Some(score) = config.oom_score_adj {
+        set_oom_score_adj(child_pid, score)?;
     }
 
-    Ok(())
+    if uid != 0 {
+        limit_process(&NoopLimiter, &config.limits, child_pid.as_raw())?;
+        return Ok(None);
+    }
+
+    if config.isolates(CloneFlags::CLONE_NEWNS) {
+        fs::create_overlay_dirs(&fs::overlay_scratch("fs", &config.fs), config.fs.persist)?;
+    }
+
+    if config.creates_network() {
+        net::setup_network_host(&config.bridge, container_net_cidr)?;
+        net::move_into_container(child_pid)?;
+    }
+
+    let mut cg = Cgroup::new(
+        &config.cgroup_root,
+        config.cgroup_parent.as_deref(),
+        &config.limits,
+    )?;
+    if config.keep_cgroup {
+        cg.keep_after_drop();
+    }
+    limit_process(&cg, &config.limits, child_pid.as_raw())?;
+    Ok(Some(cg))
+}
+
+/// Shifts the monotonic and boot time clocks of the time namespace the children of the
+/// current process are created in. Fails once a process has entered the namespace
+fn set_time_offset(seconds: i64) -> anyhow::Result<()> {
+    let offsets = format!("monotonic {} 0\nboottime {} 0\n", seconds, seconds);
+    std::fs::write("/proc/self/timens_offsets", offsets)
+        .with_context(|| format!("failed to set time offset of {} seconds", seconds))
 }
 
 fn wait_for_child(pid: Pid) -> anyhow::Result<i32> {
     use nix::sys::wait::{WaitStatus, waitpid};
 
-    let result = match waitpid(pid, None).context("Failed to wait for child process")? {
-        WaitStatus::Exited(_, code) => Ok(code),
-        WaitStatus::Signaled(_, signal, _) => Ok(128 + signal as i32),
-        _ => Ok(1),
-    };
+    loop {
+        // a signal delivered to the runtime interrupts waitpid, keep waiting
+        match waitpid(pid, None) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(e).context("Failed to wait for child process"),
+            Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            Ok(_) => return Ok(1),
+        }
+    }
+}
 
-    result
+/// Sets how likely the kernel is to pick the process and its future children when the host
+/// runs out of memory, from -1000 (never) to 1000 (first). Lowering it requires privileges.
+fn set_oom_score_adj(pid: Pid, score: i32) -> anyhow::Result<()> {
+    write_proc_file(pid, "oom_score_adj", &score.to_string())
+        .context("failed to set the OOM score adjustment")
 }
 
 fn write_proc_file(child_pid: Pid, file_name: &str, data: &str) -> anyhow::Result<()> {
@@ -276,3 +1632,697 @@ fn write_proc_file(child_pid: Pid, file_name: &str, data: &str) -> anyhow::Resul
     std::fs::write(&path, data).with_context(|| format!("failed to write to {}", path))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGINT").unwrap(), Signal::SIGINT);
+        assert_eq!(parse_signal("quit").unwrap(), Signal::SIGQUIT);
+        assert_eq!(parse_signal("HUP").unwrap(), Signal::SIGHUP);
+        assert_eq!(parse_signal("15").unwrap(), Signal::SIGTERM);
+    }
+
+    #[test]
+    fn test_run_outcome_json() {
+        let outcome = RunOutcome {
+            id: String::from("0123456789abcdef"),
+            pid: 42,
+            exit_code: 3,
+            killed: false,
+            timed_out: false,
+            interrupted: false,
+            hostname: Some(String::from("box")),
+            cpu: None,
+            mem: Some(String::from("128M")),
+            ip: Some(Ipv4Addr::new(192, 168, 200, 2)),
+            labels: vec![
+                (String::from("app"), String::from("web")),
+                (String::from("note"), String::from("say \"hi\"\n\\o/")),
+            ],
+        };
+        let json = outcome.to_json();
+        assert!(!json.contains('\n'));
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["id"], "0123456789abcdef");
+        assert_eq!(parsed["pid"], 42);
+        assert_eq!(parsed["status"], "exited");
+        assert_eq!(parsed["exit_code"], 3);
+        assert_eq!(parsed["hostname"], "box");
+        assert!(parsed["limits"]["cpu"].is_null());
+        assert_eq!(parsed["limits"]["mem"], "128M");
+        assert_eq!(parsed["ip"], "192.168.200.2");
+        assert_eq!(parsed["labels"]["app"], "web");
+        assert_eq!(parsed["labels"]["note"], "say \"hi\"\n\\o/");
+
+        let killed = RunOutcome {
+            killed: true,
+            hostname: None,
+            labels: Vec::new(),
+            ..outcome
+        };
+        let parsed: serde_json::Value = serde_json::from_str(&killed.to_json()).unwrap();
+        assert_eq!(parsed["status"], "killed");
+        assert!(parsed["hostname"].is_null());
+        assert_eq!(parsed["labels"], serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_generate_container_id() {
+        let id = generate_container_id().unwrap();
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, generate_container_id().unwrap());
+    }
+
+    #[test]
+    fn test_write_cidfile() {
+        let path =
+            std::env::temp_dir().join(format!("toy_container_cidfile_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        write_cidfile(&path, "0123456789abcdef", false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789abcdef");
+
+        assert!(write_cidfile(&path, "fedcba9876543210", false).is_err());
+        write_cidfile(&path, "fedcba98", true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fedcba98");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("app=web").unwrap(),
+            (String::from("app"), String::from("web"))
+        );
+        assert_eq!(
+            parse_label("org.example/role=a=b").unwrap(),
+            (String::from("org.example/role"), String::from("a=b"))
+        );
+        assert_eq!(parse_label("empty=").unwrap().1, "");
+
+        assert!(parse_label("novalue").is_err());
+        assert!(parse_label("=value").is_err());
+        assert!(parse_label(".app=web").is_err());
+        assert!(parse_label("my app=web").is_err());
+    }
+
+    #[test]
+    fn test_parse_sysctl() {
+        assert_eq!(
+            parse_sysctl("net.core.somaxconn=1024").unwrap(),
+            (String::from("net.core.somaxconn"), String::from("1024"))
+        );
+        assert_eq!(
+            parse_sysctl("net.ipv4.ip_local_port_range=1024 65000")
+                .unwrap()
+                .1,
+            "1024 65000"
+        );
+        assert!(parse_sysctl("kernel.domainname=example.com").is_ok());
+
+        assert!(parse_sysctl("vm.swappiness=10").is_err());
+        assert!(parse_sysctl("kernel.pid_max=100").is_err());
+        assert!(parse_sysctl("net.core.somaxconn").is_err());
+        assert!(parse_sysctl("net..somaxconn=1").is_err());
+        assert!(parse_sysctl("net.core/../../vm=1").is_err());
+        assert_eq!(
+            sysctl_path("net.ipv4.ip_forward"),
+            PathBuf::from("/proc/sys/net/ipv4/ip_forward")
+        );
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        assert!(validate_hostname("box").is_ok());
+        assert!(validate_hostname("my-box01").is_ok());
+        assert!(validate_hostname("example.org").is_ok());
+
+        assert!(validate_hostname("").is_err());
+        assert!(validate_hostname("-box").is_err());
+        assert!(validate_hostname("box-").is_err());
+        assert!(validate_hostname("my_box").is_err());
+        assert!(validate_hostname("example..org").is_err());
+        assert!(validate_hostname(&"a".repeat(65)).is_err());
+    }
+
+    extern "C" fn noop_handler(_: libc::c_int) {}
+
+    #[test]
+    fn test_wait_for_child_survives_eintr() {
+        use nix::sys::signal::{SaFlags, SigAction, SigHandler, sigaction};
+
+        // no SA_RESTART, so the signal interrupts waitpid
+        let action = SigAction::new(
+            SigHandler::Handler(noop_handler),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &action) }.unwrap();
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                std::thread::sleep(Duration::from_millis(200));
+                unsafe { libc::_exit(7) };
+            }
+            ForkResult::Parent { child } => {
+                let waiter = unsafe { libc::pthread_self() };
+                let signaller = thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(50));
+                    unsafe { libc::pthread_kill(waiter, libc::SIGUSR1) };
+                });
+                assert_eq!(wait_for_child(child).unwrap(), 7);
+                signaller.join().unwrap();
+            }
+        }
+    }
+
+    /// Runs a child which exits on SIGTERM or ignores it, asks it to stop with a short
+    /// timeout and returns its exit code and whether it had to be killed
+    fn stop_child(ignore_sigterm: bool) -> (i32, bool) {
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                if ignore_sigterm {
+                    unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+                }
+                let _ = write(&write_fd, b"1");
+                loop {
+                    unsafe { libc::pause() };
+                }
+            }
+            ForkResult::Parent { child } => {
+                drop(write_fd);
+                read(read_fd, &mut [0u8]).unwrap();
+
+                let mut request = SigSet::empty();
+                request.add(Signal::SIGUSR2);
+                request.thread_block().unwrap();
+                unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR2) };
+
+                let init = pidfd_open(child).unwrap();
+                let exited = AtomicBool::new(false);
+                let interrupted = AtomicBool::new(false);
+                let result = thread::scope(|scope| {
+                    let waiter = scope.spawn(|| {
+                        let code = wait_for_child(child).unwrap();
+                        exited.store(true, Ordering::Relaxed);
+                        code
+                    });
+                    let killed = handle_stop_requests(&StopContext {
+                        init: &init,
+                        cgroup: None,
+                        stop_signal: Signal::SIGTERM,
+                        freeze_on_stop: false,
+                        stop_timeout: Duration::from_millis(300),
+                        signals: &request,
+                        exited: &exited,
+                        interrupted: &interrupted,
+                    });
+                    (waiter.join().unwrap(), killed)
+                });
+                assert!(interrupted.load(Ordering::Relaxed));
+                result
+            }
+        }
+    }
+
+    #[test]
+    fn test_stop_graceful() {
+        assert_eq!(stop_child(false), (128 + libc::SIGTERM, false));
+    }
+
+    #[test]
+    fn test_stop_kills_after_timeout() {
+        assert_eq!(stop_child(true), (128 + libc::SIGKILL, true));
+    }
+
+    /// Exits with the number of the signal received
+    extern "C" fn exit_with_signal(signal: libc::c_int) {
+        unsafe { libc::_exit(signal) };
+    }
+
+    #[test]
+    fn test_init_translates_stop_request() {
+        use nix::sys::signal::{SaFlags, SigAction, SigHandler, sigaction};
+
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // block the stop request until init reads it, like the container's init
+                // which starts with all signals blocked
+                let mut signals = SigSet::empty();
+                signals.add(Signal::SIGTERM);
+                signals.add(Signal::SIGINT);
+                let _ = signals.thread_block();
+                let command = match unsafe { fork() } {
+                    Ok(ForkResult::Child) => unsafe {
+                        let action = SigAction::new(
+                            SigHandler::Handler(exit_with_signal),
+                            SaFlags::empty(),
+                            SigSet::empty(),
+                        );
+                        let _ = sigaction(Signal::SIGINT, &action);
+                        let _ = sigaction(Signal::SIGTERM, &action);
+                        let _ = signals.thread_unblock();
+                        let _ = write(&write_fd, b"1");
+                        loop {
+                            libc::pause();
+                        }
+                    },
+                    Ok(ForkResult::Parent { child }) => child,
+                    Err(_) => unsafe { libc::_exit(1) },
+                };
+                drop(write_fd);
+                let _ = run_init(command, Signal::SIGINT);
+                unsafe { libc::_exit(1) };
+            }
+            ForkResult::Parent { child } => {
+                drop(write_fd);
+                read(read_fd, &mut [0u8]).unwrap();
+                kill(child, Signal::SIGTERM).unwrap();
+                // init exits with the command's exit code
+                assert_eq!(wait_for_child(child).unwrap(), libc::SIGINT);
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch_commands_receive_sigterm() {
+        let commands = vec![vec!["first".to_string()], vec!["second".to_string()]];
+        let config = ContainerConfig::default();
+        // every command stands in for a container which runs until it gets SIGTERM
+        let run = |_: &str, _: &[String]| match unsafe { fork() }? {
+            ForkResult::Child => {
+                // a blocked SIGTERM never arrives, the alarm ends the wait instead
+                unsafe {
+                    libc::alarm(5);
+                    libc::pause();
+                    libc::_exit(0)
+                }
+            }
+            ForkResult::Parent { child } => {
+                let stopper = thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100));
+                    kill(child, Signal::SIGTERM)
+                });
+                let (termination, _) =
+                    with_stop_signals_blocked(|signals| supervise(child, None, signals, &config));
+                stopper.join().unwrap()?;
+                Ok(termination?.exit_code)
+            }
+        };
+        let results = crate::batch::run_commands(&commands, run, |_| false);
+        let codes: Vec<i32> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(codes, vec![128 + libc::SIGTERM; 2]);
+    }
+
+    #[test]
+    fn test_late_stop_request() {
+        // a request arriving after the container was supervised, e.g. during the teardown
+        let ((), late) = with_stop_signals_blocked(|_| unsafe {
+            libc::raise(libc::SIGTERM);
+        });
+        assert!(late);
+        let ((), late) = with_stop_signals_blocked(|_| ());
+        assert!(!late);
+        let mask = SigSet::thread_get_mask().unwrap();
+        assert!(!mask.contains(Signal::SIGTERM));
+    }
+
+    /// Runs a child which sleeps for `run_for` under a watchdog with a short timeout and
+    /// returns its exit code and whether the watchdog fired
+    fn run_with_watchdog(run_for: Duration) -> (i32, bool) {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                thread::sleep(run_for);
+                unsafe { libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => {
+                let init = pidfd_open(child).unwrap();
+                let exited = AtomicBool::new(false);
+                let timed_out = AtomicBool::new(false);
+                let exit_code = thread::scope(|scope| {
+                    scope.spawn(|| {
+                        watchdog(&init, None, Duration::from_millis(200), &exited, &timed_out)
+                    });
+                    let code = wait_for_child(child).unwrap();
+                    exited.store(true, Ordering::Relaxed);
+                    code
+                });
+                (exit_code, timed_out.load(Ordering::Relaxed))
+            }
+        }
+    }
+
+    #[test]
+    fn test_watchdog_kills_on_timeout() {
+        assert_eq!(
+            run_with_watchdog(Duration::from_secs(10)),
+            (128 + libc::SIGKILL, true)
+        );
+    }
+
+    #[test]
+    fn test_watchdog_idle_when_command_exits() {
+        assert_eq!(run_with_watchdog(Duration::from_millis(10)), (0, false));
+    }
+
+    #[test]
+    fn test_clone_error() {
+        let err = clone_error(nix::errno::Errno::EAGAIN);
+        assert!(err.to_string().contains("RLIMIT_NPROC, ulimit -u"));
+        assert!(err.to_string().contains("pids.max"));
+
+        let err = clone_error(nix::errno::Errno::EPERM);
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to clone process: EPERM: Operation not permitted"
+        );
+    }
+
+    #[test]
+    fn test_release_crashed_child() {
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // crash before reading the sync byte, without leaving a core dump
+                let no_core = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                unsafe {
+                    libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+                    libc::abort();
+                }
+            }
+            ForkResult::Parent { child } => {
+                drop(read_fd);
+                peek_exit_status(child, true).unwrap();
+
+                let err = release_child(child, &write_fd).unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "container failed during initialization, killed by SIGABRT"
+                );
+                // the child is left for the teardown to reap
+                assert_eq!(wait_for_child(child).unwrap(), 128 + libc::SIGABRT);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_hostname_file() {
+        let path =
+            std::env::temp_dir().join(format!("toy_container_hostname_{}", std::process::id()));
+        std::fs::write(&path, "  box01 \nignored\n").unwrap();
+        assert_eq!(read_hostname_file(&path).unwrap(), "box01");
+
+        std::fs::write(&path, "not a hostname\n").unwrap();
+        assert!(read_hostname_file(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_hostname_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_disable_aslr() {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let ok = disable_aslr().is_ok()
+                    && unsafe { libc::personality(0xffffffff) } & libc::ADDR_NO_RANDOMIZE != 0;
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(wait_for_child(child).unwrap(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_oom_score_adj() {
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // wait until the parent closes the pipe
+                drop(write_fd);
+                let _ = read(read_fd, &mut [0u8]);
+                unsafe { libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => {
+                drop(read_fd);
+                // raising the score needs no privileges
+                set_oom_score_adj(child, 500).unwrap();
+                let score = std::fs::read_to_string(format!("/proc/{}/oom_score_adj", child));
+                drop(write_fd);
+                assert_eq!(wait_for_child(child).unwrap(), 0);
+                assert_eq!(score.unwrap().trim(), "500");
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_id_map() {
+        assert!(check_id_map("uid_map", "         0       1000          1\n").is_ok());
+        assert!(check_id_map("uid_map", "").is_err());
+        assert!(check_id_map("gid_map", "      1000          0          1\n").is_err());
+    }
+
+    #[test]
+    fn test_verify_id_maps_without_mapping() {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // a fresh user namespace has no mappings until the parent writes them
+                let unmapped = nix::sched::unshare(CloneFlags::CLONE_NEWUSER).is_ok()
+                    && verify_id_maps().is_err();
+                unsafe { libc::_exit(if unmapped { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(wait_for_child(child).unwrap(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_capability() {
+        assert_eq!(
+            parse_capability("NET_ADMIN").unwrap(),
+            Capability::CAP_NET_ADMIN
+        );
+        assert_eq!(parse_capability("cap_kill").unwrap(), Capability::CAP_KILL);
+        assert!(parse_capability("FLY").is_err());
+    }
+
+    #[test]
+    fn test_drop_caps_keeps_requested() {
+        if !caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP).unwrap_or(false) {
+            // trimming the bounding set needs CAP_SETPCAP
+            return;
+        }
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let ok = drop_caps(&[Capability::CAP_NET_BIND_SERVICE]).is_ok()
+                    && caps::read(None, CapSet::Bounding).is_ok_and(|bounding| {
+                        bounding
+                            == [Capability::CAP_CHOWN, Capability::CAP_NET_BIND_SERVICE]
+                                .into_iter()
+                                .collect()
+                    })
+                    // a setuid-root binary can't raise a dropped capability again
+                    && nix::sys::prctl::get_no_new_privs().unwrap_or(false);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(wait_for_child(child).unwrap(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_teardown_order() {
+        let mut steps = Vec::new();
+        teardown(|step| {
+            steps.push(step);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(steps, TeardownStep::ORDER);
+    }
+
+    #[test]
+    fn test_teardown_continues_after_failure() {
+        let mut steps = Vec::new();
+        let err = teardown(|step| {
+            steps.push(step);
+            match step {
+                TeardownStep::Cgroup => anyhow::bail!("cgroup busy"),
+                TeardownStep::Network => anyhow::bail!("no bridge"),
+                _ => Ok(()),
+            }
+        })
+        .unwrap_err();
+        assert_eq!(steps, TeardownStep::ORDER);
+        assert!(format!("{:#}", err).contains("cgroup busy"));
+    }
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(parse_umask("022").unwrap(), 0o022);
+        assert_eq!(parse_umask("0027").unwrap(), 0o027);
+        assert_eq!(parse_umask("777").unwrap(), 0o777);
+        assert!(parse_umask("1000").is_err());
+        assert!(parse_umask("089").is_err());
+        assert!(parse_umask("").is_err());
+    }
+
+    #[test]
+    fn test_parse_signal_invalid() {
+        assert!(parse_signal("SIGFOO").is_err());
+        assert!(parse_signal("0").is_err());
+        assert!(parse_signal("999").is_err());
+        assert!(parse_signal("").is_err());
+    }
+
+    #[test]
+    fn test_parse_isolate() {
+        assert_eq!(
+            parse_isolate(ALL_NAMESPACES).unwrap(),
+            CloneFlags::CLONE_NEWPID
+                | CloneFlags::CLONE_NEWNET
+                | CloneFlags::CLONE_NEWNS
+                | CloneFlags::CLONE_NEWUTS
+                | CloneFlags::CLONE_NEWIPC
+                | CloneFlags::CLONE_NEWUSER
+                | CloneFlags::CLONE_NEWCGROUP
+        );
+        assert_eq!(parse_isolate("uts").unwrap(), CloneFlags::CLONE_NEWUTS);
+        assert_eq!(
+            parse_isolate("mount,user").unwrap(),
+            CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER
+        );
+        assert!(parse_isolate("").is_err());
+        assert!(parse_isolate("uts,").is_err());
+        assert_eq!(parse_isolate("time").unwrap(), CLONE_NEWTIME);
+        assert!(parse_isolate("cpu").is_err());
+        assert_eq!(
+            parse_isolate("ipc,cgroup").unwrap(),
+            CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWCGROUP
+        );
+        assert_eq!(namespace_name(CloneFlags::CLONE_NEWNS), "mount");
+    }
+
+    #[test]
+    fn test_clone_flags() {
+        let all = parse_isolate(ALL_NAMESPACES).unwrap();
+        assert_eq!(clone_flags(all, false), all - CloneFlags::CLONE_NEWCGROUP);
+        assert!(!clone_flags(all, true).contains(CloneFlags::CLONE_NEWNET));
+
+        let namespaces = parse_isolate("uts,ipc,cgroup").unwrap();
+        assert_eq!(
+            clone_flags(namespaces | CLONE_NEWTIME, false),
+            CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWIPC
+        );
+        assert_eq!(
+            clone_flags(parse_isolate("net").unwrap(), true),
+            CloneFlags::empty()
+        );
+    }
+
+    #[test]
+    fn test_isolate_uts_keeps_pid_namespace() {
+        let host_pid_ns = std::fs::read_link("/proc/self/ns/pid").unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // the user namespace allows setting the hostname without root
+                let namespaces = parse_isolate("uts,user").unwrap();
+                let ok = nix::sched::unshare(namespaces).is_ok()
+                    && sethostname("isolated-box").is_ok()
+                    && nix::unistd::gethostname().is_ok_and(|name| name == "isolated-box")
+                    && std::fs::read_link("/proc/self/ns/pid").is_ok_and(|ns| ns == host_pid_ns);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(wait_for_child(child).unwrap(), 0);
+                assert_ne!(nix::unistd::gethostname().unwrap(), "isolated-box");
+            }
+        }
+    }
+
+    /// Reads a clock in whole seconds
+    fn clock_secs(clock: libc::clockid_t) -> i64 {
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(clock, &mut now) };
+        now.tv_sec
+    }
+
+    #[test]
+    fn test_time_offset() {
+        if !Path::new("/proc/self/ns/time").exists() {
+            // no time namespaces before Linux 5.6
+            return;
+        }
+        const OFFSET: i64 = 100_000;
+
+        let before = clock_secs(libc::CLOCK_MONOTONIC);
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // the user namespace grants CAP_SYS_TIME over the new time namespace
+                let shifted = nix::sched::unshare(CloneFlags::CLONE_NEWUSER | CLONE_NEWTIME)
+                    .is_ok()
+                    && set_time_offset(OFFSET).is_ok();
+                if !shifted {
+                    unsafe { libc::_exit(1) };
+                }
+                // the offset applies to children only
+                match unsafe { fork() } {
+                    Ok(ForkResult::Child) => {
+                        let now = clock_secs(libc::CLOCK_MONOTONIC);
+                        let boot = clock_secs(libc::CLOCK_BOOTTIME);
+                        let ok = now >= before + OFFSET && boot >= before + OFFSET;
+                        unsafe { libc::_exit(if ok { 0 } else { 2 }) };
+                    }
+                    Ok(ForkResult::Parent { child }) => {
+                        let code = wait_for_child(child).unwrap_or(3);
+                        unsafe { libc::_exit(code) };
+                    }
+                    Err(_) => unsafe { libc::_exit(4) },
+                }
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(wait_for_child(child).unwrap(), 0);
+                assert!(clock_secs(libc::CLOCK_MONOTONIC) < before + OFFSET);
+            }
+        }
+    }
+
+    #[test]
+    fn test_termination_preserve_dir() {
+        let interrupted = Termination {
+            exit_code: 128 + libc::SIGTERM,
+            killed: false,
+            timed_out: false,
+            interrupted: true,
+        };
+        assert_eq!(
+            interrupted.preserve_dir("abc", true, true),
+            Some(PathBuf::from("fs/interrupted/abc"))
+        );
+        assert_eq!(
+            interrupted.preserve_dir("abc", true, false),
+            Some(PathBuf::from("fs/failed/abc"))
+        );
+        assert_eq!(interrupted.preserve_dir("abc", false, false), None);
+
+        let succeeded = Termination {
+            exit_code: 0,
+            interrupted: false,
+            ..interrupted
+        };
+        assert_eq!(succeeded.preserve_dir("abc", true, true), None);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{io::Write, os::unix::net::UnixStream, path::Path};
+
+use serde::Serialize;
+
+/// Container lifecycle event reported to the events socket
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub(crate) enum Event {
+    Start { pid: i32 },
+    Oom { pid: i32, oom_kills: u64 },
+    Exit { pid: i32, exit_code: i32 },
+}
+
+impl Event {
+    /// Formats the event as a single line JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("events serialize to JSON")
+    }
+}
+
+/// Writes events as JSON lines to a unix socket. Events are dropped when there is no socket
+/// or after the listener went away, monitoring never fails the container.
+pub(crate) struct EventSink {
+    stream: Option<UnixStream>,
+}
+
+impl EventSink {
+    /// Connects to the socket at `path`, warns and continues without events if that fails
+    pub fn connect(path: Option<&Path>) -> Self {
+        let stream = path.and_then(|path| match UnixStream::connect(path) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                eprintln!(
+                    "warning: failed to connect to events socket {:?}: {}",
+                    path, e
+                );
+                None
+            }
+        });
+        EventSink { stream }
+    }
+
+    /// Sends `event` to the socket
+    pub fn send(&mut self, event: &Event) {
+        if let Some(stream) = &mut self.stream
+            && let Err(e) = writeln!(stream, "{}", event.to_json())
+        {
+            eprintln!("warning: failed to send event, events disabled: {}", e);
+            self.stream = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix::net::UnixListener,
+    };
+
+    /// Parses a JSON line sent for an event
+    fn parse(line: &str) -> serde_json::Value {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn test_event_json() {
+        let start = parse(&Event::Start { pid: 42 }.to_json());
+        assert_eq!(start, serde_json::json!({"event": "start", "pid": 42}));
+
+        let oom = parse(
+            &Event::Oom {
+                pid: 42,
+                oom_kills: 2,
+            }
+            .to_json(),
+        );
+        assert_eq!(oom["event"], "oom");
+        assert_eq!(oom["oom_kills"], 2);
+
+        let exit = parse(
+            &Event::Exit {
+                pid: 42,
+                exit_code: 3,
+            }
+            .to_json(),
+        );
+        assert_eq!(exit["event"], "exit");
+        assert_eq!(exit["pid"], 42);
+        assert_eq!(exit["exit_code"], 3);
+    }
+
+    #[test]
+    fn test_events_sent_to_socket() {
+        let path =
+            std::env::temp_dir().join(format!("toy_container_events_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut sink = EventSink::connect(Some(&path));
+        sink.send(&Event::Start { pid: 7 });
+        sink.send(&Event::Exit {
+            pid: 7,
+            exit_code: 0,
+        });
+        drop(sink);
+
+        let (stream, _) = listener.accept().unwrap();
+        let events: Vec<serde_json::Value> = BufReader::new(stream)
+            .lines()
+            .map(|line| parse(&line.unwrap()))
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "start");
+        assert_eq!(events[1]["event"], "exit");
+        assert_eq!(events[1]["pid"], 7);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_socket_is_not_fatal() {
+        let mut sink = EventSink::connect(Some(Path::new("/nonexistent/events.sock")));
+        sink.send(&Event::Start { pid: 1 });
+        assert!(sink.stream.is_none());
+    }
+}
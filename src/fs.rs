@@ -4,10 +4,454 @@ use nix::{
     unistd::{chdir, pivot_root},
 };
 use std::{
-    fs::{create_dir_all, remove_dir, remove_dir_all},
-    path::Path,
+    fs::{Permissions, create_dir_all, remove_dir, remove_dir_all},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process::Command,
 };
 
+use crate::cgroups::format_bytes;
+
+/// Sensitive procfs and sysfs entries hidden from the container
+const DEFAULT_MASKED_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/sys/firmware",
+];
+
+/// Default size limit of tmpfs mounts
+pub const DEFAULT_TMPFS_SIZE: &str = "64m";
+
+/// Container filesystem settings
+#[derive(Debug, Default)]
+pub struct FsConfig {
+    /// Bind and tmpfs mounts requested by the user
+    pub mounts: Vec<MountSpec>,
+    /// Paths hidden from the container in addition to [DEFAULT_MASKED_PATHS]
+    pub masked_paths: Vec<PathBuf>,
+    /// Paths made read-only inside the container
+    pub read_only_paths: Vec<PathBuf>,
+    /// Don't mount a fresh /proc, reuse the inherited one instead. Processes in /proc are
+    /// then listed as seen from the parent PID namespace, not the container's
+    pub no_proc: bool,
+    /// Keep the upper layer between runs
+    pub persist: bool,
+    /// Don't mount a tmpfs at /run
+    pub no_run_tmpfs: bool,
+    /// Size limit of writable tmpfs mounts which don't specify their own size
+    pub tmpfs_size: String,
+    /// Expose the host's /dev and skip masking of sensitive paths. Insecure
+    pub privileged: bool,
+    /// Host device nodes bind-mounted into the container
+    pub devices: Vec<Device>,
+    /// Size limit in bytes of the writable layer, which is then kept in a loop-mounted ext4 image
+    pub rootfs_size: Option<u64>,
+    /// Print the options of the overlay mount before mounting it, for debugging layer order
+    pub print_mount_opts: bool,
+}
+
+/// Most lower layers an overlay can stack, OVL_MAX_STACK in the kernel
+const MAX_LOWER_LAYERS: usize = 500;
+/// Longest mount option string the kernel accepts, one page minus the terminating NUL
+const MAX_MOUNT_OPTIONS_LEN: usize = 4095;
+
+/// Mount point of the size-limited filesystem for the writable layer, relative to the fs root
+const SCRATCH_DIR: &str = "scratch";
+/// Image file backing [SCRATCH_DIR]
+const SCRATCH_IMAGE: &str = "scratch.img";
+
+/// Host path bind-mounted into the container
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub read_only: bool,
+    /// Propagation of mount events below the volume, private like the rest of the container
+    /// filesystem if not set
+    pub propagation: Option<Propagation>,
+}
+
+/// Mount propagation of a volume, see mount_namespaces(7)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Propagation {
+    /// Receives mounts made below the host path and passes mounts made in the container on
+    /// to the container's other copies of the volume
+    Shared,
+    /// Receives mounts made below the host path after the container started
+    Slave,
+    /// Neither receives nor passes on mounts
+    Private,
+}
+
+impl Propagation {
+    /// Parses a propagation name as used in `--volume` and `--mount`
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "shared" => Some(Propagation::Shared),
+            "slave" => Some(Propagation::Slave),
+            "private" => Some(Propagation::Private),
+            _ => None,
+        }
+    }
+
+    fn flag(self) -> MsFlags {
+        match self {
+            Propagation::Shared => MsFlags::MS_SHARED,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::Private => MsFlags::MS_PRIVATE,
+        }
+    }
+
+    /// Whether the volume receives mount events from the host
+    fn receives_host_mounts(self) -> bool {
+        self != Propagation::Private
+    }
+}
+
+/// Host device passed into the container with `--device`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub host: PathBuf,
+    pub container: PathBuf,
+    /// Requested access, a combination of r (read), w (write) and m (mknod)
+    pub perms: String,
+}
+
+impl Device {
+    /// Device passed into the container at the same path with full access
+    pub fn new(host: &str) -> Self {
+        Device {
+            host: PathBuf::from(host),
+            container: PathBuf::from(host),
+            perms: String::from("rwm"),
+        }
+    }
+}
+
+/// Mount requested by the user with `--volume`, `--tmpfs` or `--mount`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MountSpec {
+    Bind(Volume),
+    Tmpfs {
+        target: PathBuf,
+        size: Option<String>,
+        read_only: bool,
+    },
+}
+
+impl MountSpec {
+    /// Absolute path of the mount point inside the container
+    pub fn target(&self) -> &Path {
+        match self {
+            MountSpec::Bind(volume) => &volume.target,
+            MountSpec::Tmpfs { target, .. } => target,
+        }
+    }
+
+    /// Source, filesystem type, flags and data for the mount(2) call creating the mount.
+    /// tmpfs mounts without an explicit size get `default_tmpfs_size`.
+    fn mount_args(
+        &self,
+        default_tmpfs_size: &str,
+    ) -> (&Path, Option<&str>, MsFlags, Option<String>) {
+        match self {
+            MountSpec::Bind(volume) => (
+                &volume.source,
+                None,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None,
+            ),
+            MountSpec::Tmpfs {
+                size, read_only, ..
+            } => {
+                let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+                if *read_only {
+                    flags |= MsFlags::MS_RDONLY;
+                }
+                (
+                    Path::new("tmpfs"),
+                    Some("tmpfs"),
+                    flags,
+                    Some(format!(
+                        "size={}",
+                        size.as_deref().unwrap_or(default_tmpfs_size)
+                    )),
+                )
+            }
+        }
+    }
+}
+
+/// Validates a tmpfs size: bytes with an optional k/m/g suffix, or a percentage of RAM
+pub fn validate_tmpfs_size(size: &str) -> anyhow::Result<String> {
+    let re = regex::Regex::new(r"^(?i:[1-9]\d*[kmg]?|[1-9]\d*%)$")?;
+    if !re.is_match(size) {
+        anyhow::bail!(
+            "unsupported tmpfs size '{}'. Use bytes, units k/m/g or a percentage of RAM",
+            size
+        );
+    }
+    Ok(size.to_string())
+}
+
+/// Checks that `path` inside the container is absolute and has no `..` components, which
+/// would let a mount escape the container root, e.g. `/../../tmp`. `what` names the path
+/// in the error.
+fn check_container_path(what: &str, path: &str) -> anyhow::Result<()> {
+    if !path.starts_with('/') {
+        anyhow::bail!("{} '{}' must be an absolute path", what, path);
+    }
+    if Path::new(path)
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        anyhow::bail!("{} '{}' must not contain '..'", what, path);
+    }
+    Ok(())
+}
+
+/// Parses a `--masked-path` or `--read-only-path`, an absolute path inside the container
+pub fn parse_container_path(path: &str) -> anyhow::Result<PathBuf> {
+    check_container_path("container path", path)?;
+    Ok(PathBuf::from(path))
+}
+
+/// Parses a `--volume` shorthand into a bind mount
+pub fn parse_volume_mount(spec: &str) -> anyhow::Result<MountSpec> {
+    Ok(MountSpec::Bind(parse_volume(spec)?))
+}
+
+/// Checks that device permissions are a non-empty combination of r, w and m
+fn is_device_perms(perms: &str) -> bool {
+    !perms.is_empty()
+        && perms.chars().all(|c| "rwm".contains(c))
+        && perms
+            .chars()
+            .enumerate()
+            .all(|(i, c)| !perms[..i].contains(c))
+}
+
+/// Parses a `--device` specification in the form `HOST[:CONTAINER][:PERMS]`, e.g.
+/// `/dev/ttyUSB0`, `/dev/ttyUSB0:/dev/ttyS0` or `/dev/ttyUSB0:/dev/ttyS0:rw`
+pub fn parse_device(spec: &str) -> anyhow::Result<Device> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (host, container, perms) = match parts.as_slice() {
+        [host] => (*host, *host, "rwm"),
+        [host, perms] if is_device_perms(perms) => (*host, *host, *perms),
+        [host, container] => (*host, *container, "rwm"),
+        [host, container, perms] => (*host, *container, *perms),
+        _ => anyhow::bail!("invalid device '{}', use HOST[:CONTAINER][:PERMS]", spec),
+    };
+    if !is_device_perms(perms) {
+        anyhow::bail!(
+            "invalid device permissions '{}', use a combination of r, w and m",
+            perms
+        );
+    }
+    check_container_path("device path in the container", container)?;
+    check_device(Path::new(host))?;
+    Ok(Device {
+        host: PathBuf::from(host),
+        container: PathBuf::from(container),
+        perms: perms.to_string(),
+    })
+}
+
+/// Parses a `--tmpfs` shorthand in the form `CONTAINER[:SIZE]`, e.g. `/cache:64m`
+pub fn parse_tmpfs(spec: &str) -> anyhow::Result<MountSpec> {
+    let (target, size) = match spec.split_once(':') {
+        Some((target, size)) => (target, Some(size.to_string())),
+        None => (spec, None),
+    };
+    parse_mount(&match size {
+        Some(size) => format!("type=tmpfs,target={},tmpfs-size={}", target, size),
+        None => format!("type=tmpfs,target={}", target),
+    })
+}
+
+/// Parses a mount specification of comma-separated `key=value` options, e.g.
+/// `type=bind,source=/data,target=/data,readonly` or `type=tmpfs,target=/cache,tmpfs-size=64m`
+pub fn parse_mount(spec: &str) -> anyhow::Result<MountSpec> {
+    let mut mount_type = None;
+    let mut source = None;
+    let mut target = None;
+    let mut read_only = false;
+    let mut size = None;
+    let mut propagation = None;
+
+    for option in spec.split(',') {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+        match (key, value) {
+            ("type", Some(value)) => mount_type = Some(value),
+            ("source" | "src", Some(value)) => source = Some(value),
+            ("target" | "destination" | "dst", Some(value)) => target = Some(value),
+            ("readonly" | "ro", None | Some("true")) => read_only = true,
+            ("readonly" | "ro", Some("false")) => read_only = false,
+            ("tmpfs-size", Some(value)) => size = Some(validate_tmpfs_size(value)?),
+            ("bind-propagation", Some(value)) => {
+                propagation = Some(Propagation::parse(value).with_context(|| {
+                    format!(
+                        "unsupported bind propagation '{}', use shared, slave or private",
+                        value
+                    )
+                })?)
+            }
+            _ => anyhow::bail!("unsupported mount option '{}' in '{}'", option, spec),
+        }
+    }
+
+    let target = target.with_context(|| format!("mount '{}' has no target", spec))?;
+    check_container_path("mount target", target)?;
+    match mount_type {
+        Some("bind") => {
+            let source = source
+                .filter(|source| !source.is_empty())
+                .with_context(|| format!("bind mount '{}' has no source", spec))?;
+            if size.is_some() {
+                anyhow::bail!("tmpfs-size is not supported for bind mounts");
+            }
+            Ok(MountSpec::Bind(Volume {
+                source: PathBuf::from(source),
+                target: PathBuf::from(target),
+                read_only,
+                propagation,
+            }))
+        }
+        Some("tmpfs") => {
+            if source.is_some() {
+                anyhow::bail!("tmpfs mounts don't have a source");
+            }
+            if propagation.is_some() {
+                anyhow::bail!("bind-propagation is only supported for bind mounts");
+            }
+            Ok(MountSpec::Tmpfs {
+                target: PathBuf::from(target),
+                size,
+                read_only,
+            })
+        }
+        Some(other) => anyhow::bail!("unsupported mount type '{}', use bind or tmpfs", other),
+        None => anyhow::bail!("mount '{}' has no type", spec),
+    }
+}
+
+/// Parses a volume specification in the form `HOST:CONTAINER[:OPTIONS]`, where the options
+/// are a comma separated list of ro or rw and a propagation, e.g. `/data:/data:ro,slave`
+pub fn parse_volume(spec: &str) -> anyhow::Result<Volume> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (source, target, options) = match parts.as_slice() {
+        [source, target] => (source, target, None),
+        [source, target, options] => (source, target, Some(*options)),
+        _ => anyhow::bail!(
+            "invalid volume '{}', expected HOST:CONTAINER[:ro|rw][,shared|slave|private]",
+            spec
+        ),
+    };
+    let mut read_only = false;
+    let mut propagation = None;
+    for option in options.into_iter().flat_map(|options| options.split(',')) {
+        match option {
+            "ro" => read_only = true,
+            "rw" => read_only = false,
+            _ => {
+                propagation = Some(Propagation::parse(option).with_context(|| {
+                    format!(
+                        "unsupported volume option '{}', use ro or rw and shared, slave or \
+                         private",
+                        option
+                    )
+                })?)
+            }
+        }
+    }
+    if source.is_empty() {
+        anyhow::bail!("volume '{}' has an empty host path", spec);
+    }
+    check_container_path("volume target", target)?;
+    Ok(Volume {
+        source: PathBuf::from(source),
+        target: PathBuf::from(target),
+        read_only,
+        propagation,
+    })
+}
+
+/// Makes `dir` the working directory, so the container filesystem in its `fs` subdirectory
+/// is used. Fails without changing directory if `dir` has no `fs/rootfs`.
+pub fn enter_project_dir(dir: &Path) -> anyhow::Result<()> {
+    if !dir.join("fs/rootfs").is_dir() {
+        anyhow::bail!("{:?} has no container filesystem in fs/rootfs", dir);
+    }
+    std::env::set_current_dir(dir).with_context(|| format!("failed to change to {:?}", dir))
+}
+
+/// Directory the kernel lists registered binfmt_misc handlers in
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+/// Parses a `--qemu` path into a read-only bind mount of the qemu-user binary at the same
+/// path in the container, where binfmt_misc handlers without the F flag look it up
+pub fn parse_qemu(path: &str) -> anyhow::Result<MountSpec> {
+    check_container_path("qemu path", path)?;
+    if !Path::new(path).is_file() {
+        anyhow::bail!("qemu binary '{}' does not exist", path);
+    }
+    Ok(MountSpec::Bind(Volume {
+        source: PathBuf::from(path),
+        target: PathBuf::from(path),
+        read_only: true,
+        propagation: None,
+    }))
+}
+
+/// Extracts the interpreter of a binfmt_misc handler from its entry in [BINFMT_MISC_DIR]
+fn binfmt_interpreter(entry: &str) -> Option<&str> {
+    entry
+        .lines()
+        .find_map(|line| line.strip_prefix("interpreter "))
+        .map(str::trim)
+}
+
+/// Warns if no binfmt_misc handler on the host runs foreign binaries with `qemu`, in which
+/// case mounting it into the container doesn't help
+pub fn check_binfmt_handler(qemu: &Path) {
+    let registered = std::fs::read_dir(BINFMT_MISC_DIR).is_ok_and(|entries| {
+        entries.flatten().any(|entry| {
+            std::fs::read_to_string(entry.path()).is_ok_and(|content| {
+                binfmt_interpreter(&content).is_some_and(|i| Path::new(i) == qemu)
+            })
+        })
+    });
+    if !registered {
+        eprintln!(
+            "warning: no binfmt_misc handler in {} uses {:?}, foreign binaries won't run",
+            BINFMT_MISC_DIR, qemu
+        );
+    }
+}
+
+/// Parses a `--self-mount` target into a read-only bind mount of the running executable,
+/// so the runtime can be started again inside the container
+pub fn parse_self_mount(target: &str) -> anyhow::Result<MountSpec> {
+    check_container_path("self mount target", target)?;
+    let exe = std::env::current_exe().context("failed to find the running executable")?;
+    Ok(MountSpec::Bind(Volume {
+        source: exe,
+        target: PathBuf::from(target),
+        read_only: true,
+        propagation: None,
+    }))
+}
+
 fn recreate_dir<P: AsRef<Path>>(dir: P) -> anyhow::Result<()> {
     if dir.as_ref().exists() {
         std::fs::remove_dir_all(dir.as_ref())
@@ -18,9 +462,67 @@ fn recreate_dir<P: AsRef<Path>>(dir: P) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_overlay_dirs(root: &str) -> anyhow::Result<(String, String, String, String)> {
+/// Builds the overlayfs mount options. `userxattr` makes overlayfs keep its metadata in
+/// user.overlay.* instead of trusted.overlay.* xattrs, which an unprivileged user can't set.
+/// Needed for rootless overlays and supported since Linux 5.11.
+fn overlay_mount_options(lower: &str, upper: &str, workdir: &str, userxattr: bool) -> String {
+    let mut options = format!("lowerdir={},upperdir={},workdir={}", lower, upper, workdir);
+    if userxattr {
+        options.push_str(",userxattr");
+    }
+    options
+}
+
+/// Fails with an explanation if the overlay would exceed the kernel's limits on the number
+/// of lower layers or the length of the mount options
+fn check_overlay_limits(lower: &str, options: &str) -> anyhow::Result<()> {
+    let layers = lower.split(':').count();
+    if layers > MAX_LOWER_LAYERS {
+        anyhow::bail!(
+            "{} lower layers exceed the overlayfs limit of {}, squash some of the layers",
+            layers,
+            MAX_LOWER_LAYERS
+        );
+    }
+    if options.len() > MAX_MOUNT_OPTIONS_LEN {
+        anyhow::bail!(
+            "overlay mount options are {} bytes long with {} lower layers, more than the {} \
+             the kernel accepts. Squash some of the layers or use a shorter fs root path",
+            options.len(),
+            layers,
+            MAX_MOUNT_OPTIONS_LEN
+        );
+    }
+    Ok(())
+}
+
+/// Context for a failed overlay mount, pointing at the layers when there are many of them
+fn overlay_mount_error(lower: &str, options: &str) -> String {
+    let layers = lower.split(':').count();
+    if layers > MAX_LOWER_LAYERS / 2 || options.len() > MAX_MOUNT_OPTIONS_LEN / 2 {
+        format!(
+            "mount overlayfs with {} lower layers and {} bytes of options, the kernel may \
+             not accept that many layers, try squashing some of them",
+            layers,
+            options.len()
+        )
+    } else {
+        String::from("mount overlayfs")
+    }
+}
+
+/// Checks whether root in the current user namespace is an unprivileged user on the host,
+/// given the content of /proc/self/uid_map
+fn is_rootless_uid_map(uid_map: &str) -> bool {
+    uid_map.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        matches!(fields.as_slice(), ["0", outside, _] if *outside != "0")
+    })
+}
+
+fn get_overlay_dirs(root: &str, scratch: &str) -> anyhow::Result<(String, String, String, String)> {
     let lower_dirs = find_lower_layers(root)?;
-    let upper_dir = format!("{}/upper", root);
+    let upper_dir = format!("{}/upper", scratch);
 
     let lower = if lower_dirs.is_empty() {
         format!("{}/rootfs", root)
@@ -28,8 +530,8 @@ fn get_overlay_dirs(root: &str) -> anyhow::Result<(String, String, String, Strin
         format!("{}/rootfs:{}", root, lower_dirs)
     };
 
-    let workdir = Path::new(root).join("workdir");
-    let rootfs = Path::new(root).join("mount");
+    let workdir = Path::new(scratch).join("workdir");
+    let rootfs = Path::new(scratch).join("mount");
 
     Ok((
         lower,
@@ -39,18 +541,146 @@ fn get_overlay_dirs(root: &str) -> anyhow::Result<(String, String, String, Strin
     ))
 }
 
-pub(crate) fn create_overlay_dirs(root: &str) -> anyhow::Result<()> {
+/// Directory holding the overlay upper and work directories and the mount point: `root`
+/// itself, or the size-limited scratch filesystem with `rootfs_size`
+pub(crate) fn overlay_scratch(root: &str, config: &FsConfig) -> String {
+    match config.rootfs_size {
+        Some(_) => format!("{}/{}", root, SCRATCH_DIR),
+        None => root.to_string(),
+    }
+}
+
+/// Runs an external command, failing if it doesn't exit successfully
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to execute {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} {:?} failed with {}: {}",
+            program,
+            args,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Creates a sparse ext4 image of `size` bytes and loop-mounts it as the scratch directory
+/// in `root`, so the container can't write more than `size` to its root filesystem.
+/// Needs root and has to happen before the container's mount namespace is cloned.
+pub(crate) fn mount_scratch(root: &str, size: u64) -> anyhow::Result<()> {
+    let image = format!("{}/{}", root, SCRATCH_IMAGE);
+    let scratch = format!("{}/{}", root, SCRATCH_DIR);
+    let file =
+        std::fs::File::create(&image).with_context(|| format!("failed to create {:?}", image))?;
+    file.set_len(size)
+        .with_context(|| format!("failed to resize {:?} to {}", image, format_bytes(size)))?;
+    run("mkfs.ext4", &["-q", "-F", &image])?;
+    create_dir_all(&scratch).with_context(|| format!("failed to create {:?}", scratch))?;
+    // the loop device is released automatically when the filesystem is unmounted
+    run("mount", &["-o", "loop", &image, &scratch])?;
+    Ok(())
+}
+
+/// Unmounts the scratch filesystem and removes its image
+pub(crate) fn unmount_scratch(root: &str) -> anyhow::Result<()> {
+    let scratch = format!("{}/{}", root, SCRATCH_DIR);
+    umount2(scratch.as_str(), MntFlags::MNT_DETACH)
+        .with_context(|| format!("failed to unmount {:?}", scratch))?;
+    remove_dir(&scratch).with_context(|| format!("failed to remove {:?}", scratch))?;
+    let image = format!("{}/{}", root, SCRATCH_IMAGE);
+    std::fs::remove_file(&image).with_context(|| format!("failed to remove {:?}", image))?;
+    Ok(())
+}
+
+/// Parses a size in bytes with an optional k/m/g suffix, e.g. "512m"
+pub fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let lower = size.to_ascii_lowercase();
+    let (number, multiplier) = match lower.chars().last() {
+        Some('k') => (&lower[..lower.len() - 1], 1 << 10),
+        Some('m') => (&lower[..lower.len() - 1], 1 << 20),
+        Some('g') => (&lower[..lower.len() - 1], 1 << 30),
+        _ => (lower.as_str(), 1),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid size '{}', use bytes or units k/m/g", size))?;
+    if number == 0 {
+        anyhow::bail!("size must be greater than 0");
+    }
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("size '{}' is too large", size))
+}
+
+/// Prepares the overlay directories. The upper layer is wiped unless `persist` is set, in
+/// which case writes from previous runs are kept. The work directory is only scratch space
+/// for overlayfs and is always recreated, so a stale or corrupt one can't break the mount.
+pub(crate) fn create_overlay_dirs(root: &str, persist: bool) -> anyhow::Result<()> {
     let upper_dir = format!("{}/upper", root);
-    recreate_dir(&upper_dir)?;
+    if persist {
+        create_dir_all(&upper_dir).with_context(|| format!("failed to create {:?}", upper_dir))?;
+    } else {
+        recreate_dir(&upper_dir)?;
+    }
 
     let workdir = Path::new(root).join("workdir");
     let rootfs = Path::new(root).join("mount");
+    make_workdir_removable(&workdir);
     recreate_dir(&workdir)?;
     recreate_dir(&rootfs)?;
 
     Ok(())
 }
 
+/// overlayfs leaves a `work` directory with mode 000 in the work directory. Root can remove
+/// it anyway, a rootless user owning it has to make it accessible first.
+fn make_workdir_removable(workdir: &Path) {
+    let _ = std::fs::set_permissions(workdir.join("work"), Permissions::from_mode(0o700));
+}
+
+/// Removes the overlay work directory and the container's mount point once the container
+/// is gone. The upper layer stays until the next run, so it can be inspected.
+pub(crate) fn remove_overlay_dirs(root: &str) -> anyhow::Result<()> {
+    let workdir = Path::new(root).join("workdir");
+    if workdir.exists() {
+        make_workdir_removable(&workdir);
+        remove_dir_all(&workdir).with_context(|| format!("failed to remove {:?}", workdir))?;
+    }
+    // not recursive, a mount point that is unexpectedly still mounted must not lose its content
+    let rootfs = Path::new(root).join("mount");
+    if rootfs.exists() {
+        remove_dir(&rootfs).with_context(|| format!("failed to remove {:?}", rootfs))?;
+    }
+    Ok(())
+}
+
+/// Moves the overlay work directory, and the upper layer with `move_upper`, of a failed run
+/// to `dest`, so they survive the teardown and the next run for inspection
+pub(crate) fn preserve_overlay_dirs(
+    root: &str,
+    dest: &Path,
+    move_upper: bool,
+) -> anyhow::Result<()> {
+    create_dir_all(dest).with_context(|| format!("failed to create {:?}", dest))?;
+    let mut dirs = vec!["workdir"];
+    if move_upper {
+        dirs.push("upper");
+    }
+    for dir in dirs {
+        let source = Path::new(root).join(dir);
+        if source.exists() {
+            std::fs::rename(&source, dest.join(dir))
+                .with_context(|| format!("failed to move {:?} to {:?}", source, dest))?;
+        }
+    }
+    make_workdir_removable(&dest.join("workdir"));
+    Ok(())
+}
+
 pub fn find_lower_layers(root: &str) -> anyhow::Result<String> {
     let mut names: Vec<String> = Vec::new();
 
@@ -76,43 +706,367 @@ pub fn find_lower_layers(root: &str) -> anyhow::Result<String> {
     Ok(names.join(":"))
 }
 
+/// Makes sure the mount point for a volume exists in the container's root.
+/// Missing targets are created in the overlay, so they end up in the upper layer and
+/// are owned by the container's root user.
+fn ensure_mount_target(source: &Path, target: &Path) -> anyhow::Result<()> {
+    if target.exists() {
+        return Ok(());
+    }
+    if source.is_dir() {
+        create_dir_all(target).with_context(|| format!("failed to create {:?}", target))?;
+    } else {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).with_context(|| format!("failed to create {:?}", parent))?;
+        }
+        std::fs::File::create(target).with_context(|| format!("failed to create {:?}", target))?;
+    }
+    Ok(())
+}
+
+/// Mounts bind and tmpfs mounts into the container root mounted at `rootfs`
+fn mount_specs(
+    rootfs: &Path,
+    mounts: &[MountSpec],
+    default_tmpfs_size: &str,
+) -> anyhow::Result<()> {
+    for spec in mounts {
+        let target = container_path(rootfs, spec.target());
+        match spec {
+            MountSpec::Bind(volume) => {
+                if !volume.source.exists() {
+                    anyhow::bail!("volume source {:?} does not exist", volume.source);
+                }
+                ensure_mount_target(&volume.source, &target)?;
+            }
+            MountSpec::Tmpfs { .. } => {
+                create_dir_all(&target)
+                    .with_context(|| format!("failed to create {:?}", target))?;
+            }
+        }
+
+        let (source, fstype, flags, data) = spec.mount_args(default_tmpfs_size);
+        mount(Some(source), &target, fstype, flags, data.as_deref())
+            .with_context(|| format!("mount {:?} to {:?}", source, spec.target()))?;
+
+        // read-only bind mounts need a separate remount
+        if let MountSpec::Bind(volume) = spec
+            && volume.read_only
+        {
+            mount(
+                None::<&str>,
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+            .with_context(|| format!("remount {:?} read-only", volume.target))?;
+        }
+
+        if let MountSpec::Bind(volume) = spec
+            && let Some(propagation) = volume.propagation
+        {
+            mount(
+                None::<&str>,
+                &target,
+                None::<&str>,
+                MsFlags::MS_REC | propagation.flag(),
+                None::<&str>,
+            )
+            .with_context(|| format!("set {:?} propagation of {:?}", propagation, volume.target))?;
+        }
+    }
+    Ok(())
+}
+
+/// Propagation the container's copy of the host filesystem gets before anything is mounted.
+/// Private, unless a volume has to receive mounts from the host, which only reach it through
+/// a slave copy of the host mount it is bound from.
+fn root_propagation(mounts: &[MountSpec]) -> MsFlags {
+    let from_host = mounts.iter().any(|spec| {
+        matches!(spec, MountSpec::Bind(volume)
+            if volume.propagation.is_some_and(Propagation::receives_host_mounts))
+    });
+    if from_host {
+        MsFlags::MS_SLAVE
+    } else {
+        MsFlags::MS_PRIVATE
+    }
+}
+
+/// overlayfs requires upperdir and workdir to be on the same filesystem, otherwise the
+/// mount fails with an unhelpful error. Checks this upfront.
+fn check_same_filesystem<P: AsRef<Path>, Q: AsRef<Path>>(
+    upper: P,
+    workdir: Q,
+) -> anyhow::Result<()> {
+    let upper = upper.as_ref();
+    let workdir = workdir.as_ref();
+    let upper_dev = std::fs::metadata(upper)
+        .with_context(|| format!("failed to stat {:?}", upper))?
+        .dev();
+    let workdir_dev = std::fs::metadata(workdir)
+        .with_context(|| format!("failed to stat {:?}", workdir))?
+        .dev();
+    if upper_dev != workdir_dev {
+        anyhow::bail!(
+            "overlay upperdir {:?} and workdir {:?} are on different filesystems, overlayfs requires them to be on the same one",
+            upper,
+            workdir
+        );
+    }
+    Ok(())
+}
+
+/// Resolves an absolute container path against the container root mounted at `rootfs`
+fn container_path(rootfs: &Path, path: &Path) -> PathBuf {
+    rootfs.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Hides paths from the container: directories get an empty read-only tmpfs on top,
+/// files get /dev/null bind-mounted over them. Missing paths are skipped.
+fn mask_paths<P: AsRef<Path>>(rootfs: &Path, paths: &[P]) -> anyhow::Result<()> {
+    for path in paths {
+        let target = container_path(rootfs, path.as_ref());
+        if !target.exists() {
+            continue;
+        }
+        if target.is_dir() {
+            mount(
+                Some("tmpfs"),
+                &target,
+                Some("tmpfs"),
+                MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+        } else {
+            mount(
+                Some("/dev/null"),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+        }
+        .with_context(|| format!("mask {:?}", path.as_ref()))?;
+    }
+    Ok(())
+}
+
+/// Mounts an empty tmpfs at /run and makes /var/run point to it.
+/// If /var/run is a real directory, the /run tmpfs is bind-mounted over it.
+fn mount_run_tmpfs(rootfs: &Path, size: &str) -> anyhow::Result<()> {
+    let run = rootfs.join("run");
+    create_dir_all(&run).context("create /run")?;
+    let opts = format!("mode=755,size={}", size);
+    mount(
+        Some("tmpfs"),
+        &run,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(opts.as_str()),
+    )
+    .context("mount /run")?;
+    // /var/lock points to /run/lock in most images
+    create_dir_all(run.join("lock")).context("create /run/lock")?;
+
+    let var_run = rootfs.join("var/run");
+    match std::fs::symlink_metadata(&var_run) {
+        // a symlink is resolved inside the container, leave it as is
+        Ok(meta) if meta.file_type().is_symlink() => {}
+        Ok(meta) if meta.is_dir() => {
+            mount(
+                Some(&run),
+                &var_run,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .context("bind mount /run to /var/run")?;
+        }
+        Ok(_) => anyhow::bail!("/var/run exists and is neither a directory nor a symlink"),
+        Err(_) => {
+            create_dir_all(rootfs.join("var")).context("create /var")?;
+            std::os::unix::fs::symlink("../run", &var_run).context("symlink /var/run")?;
+        }
+    }
+    Ok(())
+}
+
+/// Makes /etc/mtab point to the container's mount table, so tools like mount and df which
+/// read it work. An existing /etc/mtab from the image is left alone
+fn link_mtab(rootfs: &Path) -> anyhow::Result<()> {
+    let mtab = rootfs.join("etc/mtab");
+    if std::fs::symlink_metadata(&mtab).is_ok() {
+        return Ok(());
+    }
+    create_dir_all(rootfs.join("etc")).context("create /etc")?;
+    std::os::unix::fs::symlink("/proc/self/mounts", &mtab).context("symlink /etc/mtab")
+}
+
+/// Bind-mounts the host's /dev with all the devices into the container
+fn mount_host_dev(rootfs: &Path) -> anyhow::Result<()> {
+    let dev = rootfs.join("dev");
+    create_dir_all(&dev).context("create /dev")?;
+    mount(
+        Some("/dev"),
+        &dev,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .context("bind mount host /dev")?;
+    Ok(())
+}
+
+/// Checks that `path` is a character or block device on the host
+fn check_device(path: &Path) -> anyhow::Result<()> {
+    let file_type = std::fs::metadata(path)
+        .with_context(|| format!("device {:?} does not exist", path))?
+        .file_type();
+    if !file_type.is_char_device() && !file_type.is_block_device() {
+        anyhow::bail!("{:?} is not a device", path);
+    }
+    Ok(())
+}
+
+/// Bind-mounts host device nodes into the container root mounted at `rootfs`.
+/// Other host devices stay invisible in the container.
+fn mount_devices(rootfs: &Path, devices: &[Device]) -> anyhow::Result<()> {
+    for device in devices {
+        check_device(&device.host)?;
+        if device.perms != "rwm" {
+            // there is no device cgroup program to restrict access to the node
+            eprintln!(
+                "warning: permissions '{}' for device {:?} are not enforced",
+                device.perms, device.host
+            );
+        }
+        let target = container_path(rootfs, &device.container);
+        ensure_mount_target(&device.host, &target)?;
+        mount(
+            Some(device.host.as_path()),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .with_context(|| format!("bind mount device {:?}", device.host))?;
+    }
+    Ok(())
+}
+
+/// Remounts paths inside the container read-only. Missing paths are skipped.
+fn make_paths_read_only(rootfs: &Path, paths: &[PathBuf]) -> anyhow::Result<()> {
+    for path in paths {
+        let target = container_path(rootfs, path);
+        if !target.exists() {
+            continue;
+        }
+        mount(
+            Some(&target),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .with_context(|| format!("bind mount {:?}", path))?;
+        mount(
+            None::<&str>,
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            None::<&str>,
+        )
+        .with_context(|| format!("remount {:?} read-only", path))?;
+    }
+    Ok(())
+}
+
 /// Create the container's filesystem.
 /// See [fs readme](fs/readme.md) for details about directory layout
-pub(crate) fn create_container_filesystem(root: &str) -> anyhow::Result<()> {
-    // change the root fs propagation to private
+pub(crate) fn create_container_filesystem(root: &str, config: &FsConfig) -> anyhow::Result<()> {
+    // keep mounts made in the container from propagating to the host
     mount(
         None::<&str>,
         "/",
         None::<&str>,
-        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        MsFlags::MS_REC | root_propagation(&config.mounts),
         None::<&str>,
     )
-    .context("private propagation for /")?;
+    .context("set propagation of /")?;
 
-    let (lower, upper, workdir, rootdir) = get_overlay_dirs(root)?;
+    let (lower, upper, workdir, rootdir) = get_overlay_dirs(root, &overlay_scratch(root, config))?;
 
     let rootfs = Path::new(&rootdir);
 
-    let mount_opts = format!("lowerdir={},upperdir={},workdir={}", lower, upper, workdir);
+    check_same_filesystem(&upper, &workdir)?;
 
-    mount(
-        Some("overlay"),
-        rootfs,
-        Some("overlay"),
-        MsFlags::empty(),
-        Some(mount_opts.as_str()),
-    )
-    .context("mount overlayfs")?;
+    let rootless = std::fs::read_to_string("/proc/self/uid_map")
+        .is_ok_and(|uid_map| is_rootless_uid_map(&uid_map));
+    // userxattr makes the options longest
+    let longest_options = overlay_mount_options(&lower, &upper, &workdir, true);
+    check_overlay_limits(&lower, &longest_options)?;
+    let mount_overlay = |userxattr| {
+        let options = overlay_mount_options(&lower, &upper, &workdir, userxattr);
+        if config.print_mount_opts {
+            println!("overlay mount options: {}", options);
+        }
+        mount(
+            Some("overlay"),
+            rootfs,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        )
+    };
+    match mount_overlay(rootless) {
+        // kernels before 5.11 don't know userxattr, and don't allow rootless overlays anyway
+        // unless patched, in which case the mount works without it
+        Err(nix::errno::Errno::EINVAL) if rootless => mount_overlay(false),
+        result => result,
+    }
+    .with_context(|| overlay_mount_error(&lower, &longest_options))?;
 
     let proc = rootfs.join("proc");
-    mount(
-        Some("proc"),
-        &proc,
-        Some("proc"),
-        MsFlags::empty(),
-        None::<&str>,
-    )
-    .context("mount /proc")?;
+    if config.no_proc {
+        // nested runtimes may not be allowed to mount procfs, so failing to reuse the
+        // inherited /proc is not fatal
+        if let Err(e) = mount(
+            Some("/proc"),
+            &proc,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        ) {
+            eprintln!("warning: failed to bind mount inherited /proc: {}", e);
+        }
+    } else {
+        mount(
+            Some("proc"),
+            &proc,
+            Some("proc"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .context("mount /proc")?;
+    }
+    link_mtab(rootfs)?;
+
+    if !config.no_run_tmpfs {
+        mount_run_tmpfs(rootfs, &config.tmpfs_size)?;
+    }
+
+    mount_specs(rootfs, &config.mounts, &config.tmpfs_size)?;
+
+    if config.privileged {
+        mount_host_dev(rootfs)?;
+    } else {
+        mount_devices(rootfs, &config.devices)?;
+        mask_paths(rootfs, DEFAULT_MASKED_PATHS)?;
+    }
+    mask_paths(rootfs, &config.masked_paths)?;
+    make_paths_read_only(rootfs, &config.read_only_paths)?;
 
     // prepare for pivot_root
     let old_root = rootfs.join(".old_root");
@@ -129,3 +1083,593 @@ pub(crate) fn create_container_filesystem(root: &str) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_volume() {
+        assert_eq!(
+            parse_volume("/tmp/data:/data").unwrap(),
+            Volume {
+                source: PathBuf::from("/tmp/data"),
+                target: PathBuf::from("/data"),
+                read_only: false,
+                propagation: None,
+            }
+        );
+        assert!(parse_volume("/tmp/data:/data:ro").unwrap().read_only);
+        assert!(!parse_volume("data:/srv/data:rw").unwrap().read_only);
+
+        let volume = parse_volume("/tmp/data:/data:ro,slave").unwrap();
+        assert!(volume.read_only);
+        assert_eq!(volume.propagation, Some(Propagation::Slave));
+        let volume = parse_volume("/tmp/data:/data:shared").unwrap();
+        assert!(!volume.read_only);
+        assert_eq!(volume.propagation, Some(Propagation::Shared));
+    }
+
+    #[test]
+    fn test_parse_volume_invalid() {
+        assert!(parse_volume("/tmp/data").is_err());
+        assert!(parse_volume(":/data").is_err());
+        assert!(parse_volume("/tmp/data:data").is_err());
+        assert!(parse_volume("/tmp/data:/data:rx").is_err());
+        assert!(parse_volume("/a:/b:ro:extra").is_err());
+        assert!(parse_volume("/a:/b:ro,rslave").is_err());
+        assert!(parse_volume("/a:/b:ro,").is_err());
+        assert!(parse_volume("/x:/../../../tmp/pwn").is_err());
+        assert!(parse_volume("/x:/data/../../etc:ro").is_err());
+    }
+
+    #[test]
+    fn test_enter_project_dir_without_fs() {
+        let cwd = std::env::current_dir().unwrap();
+        assert!(enter_project_dir(&std::env::temp_dir()).is_err());
+        assert!(enter_project_dir(Path::new("/nonexistent")).is_err());
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+    }
+
+    #[test]
+    fn test_parse_qemu() {
+        assert_eq!(
+            parse_qemu("/bin/sh").unwrap(),
+            MountSpec::Bind(Volume {
+                source: PathBuf::from("/bin/sh"),
+                target: PathBuf::from("/bin/sh"),
+                read_only: true,
+                propagation: None,
+            })
+        );
+        assert!(parse_qemu("/nonexistent/qemu-aarch64-static").is_err());
+        assert!(parse_qemu("qemu-aarch64-static").is_err());
+        assert!(parse_qemu("/usr/../bin/sh").is_err());
+    }
+
+    #[test]
+    fn test_binfmt_interpreter() {
+        let entry = "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\nmagic 7f454c46\n";
+        assert_eq!(
+            binfmt_interpreter(entry),
+            Some("/usr/bin/qemu-aarch64-static")
+        );
+        assert_eq!(binfmt_interpreter("enabled\n"), None);
+    }
+
+    #[test]
+    fn test_parse_self_mount() {
+        assert_eq!(
+            parse_self_mount("/usr/local/bin/container").unwrap(),
+            MountSpec::Bind(Volume {
+                source: std::env::current_exe().unwrap(),
+                target: PathBuf::from("/usr/local/bin/container"),
+                read_only: true,
+                propagation: None,
+            })
+        );
+        assert!(parse_self_mount("bin/container").is_err());
+        assert!(parse_self_mount("/../container").is_err());
+    }
+
+    #[test]
+    fn test_preserve_overlay_dirs() {
+        let root =
+            std::env::temp_dir().join(format!("toy_container_failed_{}", std::process::id()));
+        let root_str = root.to_str().unwrap();
+        create_overlay_dirs(root_str, false).unwrap();
+        std::fs::write(root.join("upper/written"), "data").unwrap();
+
+        let dest = root.join("failed/0123456789abcdef");
+        preserve_overlay_dirs(root_str, &dest, true).unwrap();
+        remove_overlay_dirs(root_str).unwrap();
+
+        assert!(dest.join("workdir").is_dir());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("upper/written")).unwrap(),
+            "data"
+        );
+        assert!(!root.join("workdir").exists());
+        assert!(!root.join("mount").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_create_overlay_dirs_persist() {
+        let root =
+            std::env::temp_dir().join(format!("toy_container_overlay_{}", std::process::id()));
+        let root_str = root.to_str().unwrap();
+        create_overlay_dirs(root_str, false).unwrap();
+        std::fs::write(root.join("upper/file"), "data").unwrap();
+        std::fs::write(root.join("workdir/stale"), "data").unwrap();
+
+        create_overlay_dirs(root_str, true).unwrap();
+        assert!(root.join("upper/file").exists());
+        assert!(!root.join("workdir/stale").exists());
+
+        create_overlay_dirs(root_str, false).unwrap();
+        assert!(!root.join("upper/file").exists());
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_mount_bind() {
+        let spec = parse_mount("type=bind,source=/data,target=/srv/data,readonly").unwrap();
+        assert_eq!(
+            spec,
+            MountSpec::Bind(Volume {
+                source: PathBuf::from("/data"),
+                target: PathBuf::from("/srv/data"),
+                read_only: true,
+                propagation: None,
+            })
+        );
+        assert_eq!(
+            spec.mount_args(DEFAULT_TMPFS_SIZE),
+            (
+                Path::new("/data"),
+                None,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None
+            )
+        );
+        assert_eq!(
+            parse_mount("type=bind,src=/a,dst=/b,ro=false").unwrap(),
+            parse_volume_mount("/a:/b").unwrap()
+        );
+        assert_eq!(
+            parse_mount("type=bind,src=/a,dst=/b,bind-propagation=slave").unwrap(),
+            parse_volume_mount("/a:/b:slave").unwrap()
+        );
+        assert!(parse_mount("type=bind,src=/a,dst=/b,bind-propagation=rslave").is_err());
+        assert!(parse_mount("type=tmpfs,dst=/b,bind-propagation=slave").is_err());
+    }
+
+    #[test]
+    fn test_root_propagation() {
+        let volume = |propagation| {
+            MountSpec::Bind(Volume {
+                source: PathBuf::from("/data"),
+                target: PathBuf::from("/data"),
+                read_only: false,
+                propagation,
+            })
+        };
+        assert_eq!(root_propagation(&[]), MsFlags::MS_PRIVATE);
+        assert_eq!(
+            root_propagation(&[volume(None), volume(Some(Propagation::Private))]),
+            MsFlags::MS_PRIVATE
+        );
+        assert_eq!(
+            root_propagation(&[volume(None), volume(Some(Propagation::Slave))]),
+            MsFlags::MS_SLAVE
+        );
+        assert_eq!(
+            root_propagation(&[volume(Some(Propagation::Shared))]),
+            MsFlags::MS_SLAVE
+        );
+    }
+
+    #[test]
+    fn test_slave_volume_receives_host_mounts() {
+        use nix::{
+            fcntl::OFlag,
+            sched::{CloneFlags, unshare},
+            sys::wait::{WaitStatus, waitpid},
+            unistd::{ForkResult, fork, pipe2, read, write},
+        };
+
+        let dir =
+            std::env::temp_dir().join(format!("toy_container_propagation_{}", std::process::id()));
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        create_dir_all(source.join("sub")).unwrap();
+        create_dir_all(&target).unwrap();
+
+        // the forked "host" gets its own namespaces, so the test doesn't touch the real mounts
+        let run_host = || -> anyhow::Result<i32> {
+            unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+            mount(
+                Some(&source),
+                &source,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )?;
+            mount(
+                None::<&str>,
+                &source,
+                None::<&str>,
+                MsFlags::MS_SHARED,
+                None::<&str>,
+            )?;
+            let (started_read, started_write) = pipe2(OFlag::O_CLOEXEC)?;
+            let (mounted_read, mounted_write) = pipe2(OFlag::O_CLOEXEC)?;
+            match unsafe { fork() }? {
+                ForkResult::Child => {
+                    let mounts = [MountSpec::Bind(Volume {
+                        source: source.clone(),
+                        target: target.clone(),
+                        read_only: false,
+                        propagation: Some(Propagation::Slave),
+                    })];
+                    let received = unshare(CloneFlags::CLONE_NEWNS).is_ok()
+                        && mount(
+                            None::<&str>,
+                            "/",
+                            None::<&str>,
+                            MsFlags::MS_REC | root_propagation(&mounts),
+                            None::<&str>,
+                        )
+                        .is_ok()
+                        && mount_specs(Path::new("/"), &mounts, DEFAULT_TMPFS_SIZE).is_ok()
+                        && write(&started_write, b"1").is_ok()
+                        && read(mounted_read, &mut [0u8]).is_ok_and(|n| n == 1)
+                        && std::fs::metadata(target.join("sub")).unwrap().dev()
+                            != std::fs::metadata(&target).unwrap().dev();
+                    unsafe { libc::_exit(if received { 0 } else { 1 }) };
+                }
+                ForkResult::Parent { child } => {
+                    drop(started_write);
+                    read(started_read, &mut [0u8])?;
+                    mount(
+                        Some("tmpfs"),
+                        &source.join("sub"),
+                        Some("tmpfs"),
+                        MsFlags::empty(),
+                        None::<&str>,
+                    )?;
+                    write(&mounted_write, b"1")?;
+                    match waitpid(child, None)? {
+                        WaitStatus::Exited(_, code) => Ok(code),
+                        _ => Ok(1),
+                    }
+                }
+            }
+        };
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = run_host().unwrap_or(2);
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                let status = waitpid(child, None).unwrap();
+                std::fs::remove_dir_all(&dir).unwrap();
+                assert_eq!(status, WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_tmpfs_size_limits_mount() {
+        use nix::{
+            sched::{CloneFlags, unshare},
+            sys::wait::{WaitStatus, waitpid},
+            unistd::{ForkResult, fork},
+        };
+
+        let rootfs =
+            std::env::temp_dir().join(format!("toy_container_tmpfs_size_{}", std::process::id()));
+        create_dir_all(&rootfs).unwrap();
+
+        // the tmpfs is mounted in the child's own namespaces and disappears with them
+        let fill_tmpfs = || -> anyhow::Result<i32> {
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+            // files can only be created with an owner mapped into the user namespace
+            std::fs::write("/proc/self/uid_map", format!("0 {} 1\n", uid))?;
+            std::fs::write("/proc/self/setgroups", "deny\n")?;
+            std::fs::write("/proc/self/gid_map", format!("0 {} 1\n", gid))?;
+            let mounts = [MountSpec::Tmpfs {
+                target: PathBuf::from("/data"),
+                size: None,
+                read_only: false,
+            }];
+            mount_specs(&rootfs, &mounts, "64k")?;
+            std::fs::write(rootfs.join("data/small"), vec![0u8; 16 * 1024])?;
+            match std::fs::write(rootfs.join("data/large"), vec![0u8; 128 * 1024]) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => Ok(0),
+                _ => Ok(1),
+            }
+        };
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = fill_tmpfs().unwrap_or(2);
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                let status = waitpid(child, None).unwrap();
+                std::fs::remove_dir_all(&rootfs).unwrap();
+                assert_eq!(status, WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_mount_tmpfs() {
+        let spec = parse_mount("type=tmpfs,target=/cache,tmpfs-size=64m").unwrap();
+        assert_eq!(
+            spec,
+            MountSpec::Tmpfs {
+                target: PathBuf::from("/cache"),
+                size: Some(String::from("64m")),
+                read_only: false,
+            }
+        );
+        assert_eq!(
+            spec.mount_args(DEFAULT_TMPFS_SIZE),
+            (
+                Path::new("tmpfs"),
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(String::from("size=64m"))
+            )
+        );
+        assert_eq!(parse_tmpfs("/cache:64m").unwrap(), spec);
+
+        let (_, _, flags, data) = parse_tmpfs("/scratch").unwrap().mount_args("16m");
+        assert!(!flags.contains(MsFlags::MS_RDONLY));
+        assert_eq!(data.as_deref(), Some("size=16m"));
+    }
+
+    #[test]
+    fn test_validate_tmpfs_size() {
+        assert!(validate_tmpfs_size("64m").is_ok());
+        assert!(validate_tmpfs_size("1G").is_ok());
+        assert!(validate_tmpfs_size("4096").is_ok());
+        assert!(validate_tmpfs_size("50%").is_ok());
+
+        assert!(validate_tmpfs_size("").is_err());
+        assert!(validate_tmpfs_size("0").is_err());
+        assert!(validate_tmpfs_size("64mb").is_err());
+        assert!(validate_tmpfs_size("1.5g").is_err());
+        assert!(parse_mount("type=tmpfs,target=/b,tmpfs-size=lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_mount_invalid() {
+        assert!(parse_mount("target=/data").is_err());
+        assert!(parse_mount("type=bind,target=/data").is_err());
+        assert!(parse_mount("type=bind,source=/data").is_err());
+        assert!(parse_mount("type=bind,source=/data,target=data").is_err());
+        assert!(parse_mount("type=bind,source=/a,target=/b,tmpfs-size=1m").is_err());
+        assert!(parse_mount("type=tmpfs,source=/a,target=/b").is_err());
+        assert!(parse_mount("type=nfs,target=/b").is_err());
+        assert!(parse_mount("type=tmpfs,target=/b,colour=blue").is_err());
+        assert!(parse_mount("type=bind,source=/a,target=/../../tmp").is_err());
+        assert!(parse_mount("type=tmpfs,target=/cache/..").is_err());
+        assert!(parse_tmpfs("/../tmp:64m").is_err());
+    }
+
+    #[test]
+    fn test_remove_overlay_dirs() {
+        let root =
+            std::env::temp_dir().join(format!("toy_container_teardown_{}", std::process::id()));
+        let root_str = root.to_str().unwrap();
+        create_overlay_dirs(root_str, false).unwrap();
+        std::fs::write(root.join("upper/file"), "data").unwrap();
+        create_dir_all(root.join("workdir/work")).unwrap();
+
+        remove_overlay_dirs(root_str).unwrap();
+        assert!(!root.join("workdir").exists());
+        assert!(!root.join("mount").exists());
+        assert!(root.join("upper/file").exists());
+        // nothing left to remove is fine
+        remove_overlay_dirs(root_str).unwrap();
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_mount_options() {
+        assert_eq!(
+            overlay_mount_options("fs/rootfs", "fs/upper", "fs/workdir", false),
+            "lowerdir=fs/rootfs,upperdir=fs/upper,workdir=fs/workdir"
+        );
+        assert_eq!(
+            overlay_mount_options("fs/rootfs", "fs/upper", "fs/workdir", true),
+            "lowerdir=fs/rootfs,upperdir=fs/upper,workdir=fs/workdir,userxattr"
+        );
+    }
+
+    #[test]
+    fn test_overlay_mount_options_layer_order() {
+        let root =
+            std::env::temp_dir().join(format!("toy_container_layers_{}", std::process::id()));
+        for dir in [
+            "rootfs", "layer02", "layer10", "layer01", "layer1", "scratch",
+        ] {
+            create_dir_all(root.join(dir)).unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+
+        let (lower, upper, workdir, _) = get_overlay_dirs(root_str, root_str).unwrap();
+        assert_eq!(
+            overlay_mount_options(&lower, &upper, &workdir, false),
+            format!(
+                "lowerdir={r}/rootfs:{r}/layer01:{r}/layer02:{r}/layer10,upperdir={r}/upper,workdir={r}/workdir",
+                r = root_str
+            )
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_overlay_limits() {
+        let layers = |count: usize| {
+            (0..count)
+                .map(|i| format!("fs/layer{:03}", i))
+                .collect::<Vec<_>>()
+                .join(":")
+        };
+        let options = |lower: &str| overlay_mount_options(lower, "fs/upper", "fs/workdir", true);
+
+        let lower = layers(10);
+        assert!(check_overlay_limits(&lower, &options(&lower)).is_ok());
+        assert_eq!(
+            overlay_mount_error(&lower, &options(&lower)),
+            "mount overlayfs"
+        );
+
+        let lower = layers(400);
+        let err = check_overlay_limits(&lower, &options(&lower)).unwrap_err();
+        assert!(err.to_string().contains("Squash some of the layers"));
+        assert!(overlay_mount_error(&lower, &options(&lower)).contains("400 lower layers"));
+
+        let lower = layers(MAX_LOWER_LAYERS + 1);
+        let err = check_overlay_limits(&lower, &options(&lower)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("exceed the overlayfs limit of 500")
+        );
+    }
+
+    #[test]
+    fn test_is_rootless_uid_map() {
+        assert!(is_rootless_uid_map("         0       1000          1\n"));
+        assert!(!is_rootless_uid_map("         0          0 4294967295\n"));
+        assert!(!is_rootless_uid_map("         0          0          1\n"));
+        assert!(!is_rootless_uid_map(""));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64k").unwrap(), 64 << 10);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("m").is_err());
+        assert!(parse_size("1t").is_err());
+        assert!(parse_size("99999999999999g").is_err());
+    }
+
+    #[test]
+    fn test_overlay_scratch() {
+        let mut config = FsConfig::default();
+        assert_eq!(overlay_scratch("fs", &config), "fs");
+        config.rootfs_size = Some(1 << 30);
+        assert_eq!(overlay_scratch("fs", &config), "fs/scratch");
+    }
+
+    #[test]
+    fn test_check_same_filesystem() {
+        let tmp = std::env::temp_dir();
+        assert!(check_same_filesystem(&tmp, &tmp).is_ok());
+
+        // procfs is always a separate filesystem
+        let err = check_same_filesystem(&tmp, "/proc").unwrap_err();
+        assert!(err.to_string().contains("different filesystems"));
+    }
+
+    #[test]
+    fn test_parse_container_path() {
+        assert_eq!(
+            parse_container_path("/etc/secrets").unwrap(),
+            PathBuf::from("/etc/secrets")
+        );
+        assert!(parse_container_path("etc/secrets").is_err());
+        assert!(parse_container_path("/etc/../../root").is_err());
+        assert!(parse_container_path("/..").is_err());
+    }
+
+    #[test]
+    fn test_container_path() {
+        let rootfs = Path::new("fs/mount");
+        assert_eq!(
+            container_path(rootfs, Path::new("/proc/kcore")),
+            PathBuf::from("fs/mount/proc/kcore")
+        );
+        assert_eq!(
+            container_path(rootfs, Path::new("etc/hosts")),
+            PathBuf::from("fs/mount/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn test_check_device() {
+        assert!(check_device(Path::new("/dev/null")).is_ok());
+        assert!(check_device(Path::new("/etc/hostname")).is_err());
+        assert!(check_device(Path::new("/dev/does-not-exist")).is_err());
+    }
+
+    #[test]
+    fn test_parse_device() {
+        assert_eq!(parse_device("/dev/null").unwrap(), Device::new("/dev/null"));
+        let device = parse_device("/dev/null:/dev/nothing:rw").unwrap();
+        assert_eq!(device.container, PathBuf::from("/dev/nothing"));
+        assert_eq!(device.perms, "rw");
+        assert_eq!(parse_device("/dev/null:r").unwrap().perms, "r");
+        assert_eq!(
+            parse_device("/dev/null:/dev/zero").unwrap().container,
+            PathBuf::from("/dev/zero")
+        );
+    }
+
+    #[test]
+    fn test_parse_device_invalid() {
+        assert!(parse_device("/dev/null:/dev/null:rx").is_err());
+        assert!(parse_device("/dev/null:/dev/null:rr").is_err());
+        assert!(parse_device("/dev/null:dev/null").is_err());
+        assert!(parse_device("/etc/hostname").is_err());
+        assert!(parse_device("/dev/null:/a:r:w").is_err());
+        assert!(parse_device("/dev/null:/dev/../../dev/null").is_err());
+    }
+
+    #[test]
+    fn test_ensure_mount_target_creates_missing_dirs() {
+        let base = std::env::temp_dir().join(format!("toy_container_test_{}", std::process::id()));
+        let target = base.join("not/in/rootfs");
+        ensure_mount_target(Path::new("/"), &target).unwrap();
+        assert!(target.is_dir());
+
+        let file_target = base.join("etc/some.conf");
+        ensure_mount_target(Path::new("/etc/hostname"), &file_target).unwrap();
+        assert!(file_target.is_file());
+
+        remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_link_mtab() {
+        let rootfs =
+            std::env::temp_dir().join(format!("toy_container_mtab_{}", std::process::id()));
+        link_mtab(&rootfs).unwrap();
+        assert_eq!(
+            std::fs::read_link(rootfs.join("etc/mtab")).unwrap(),
+            PathBuf::from("/proc/self/mounts")
+        );
+
+        // an image's own mtab is kept
+        std::fs::remove_file(rootfs.join("etc/mtab")).unwrap();
+        std::fs::write(rootfs.join("etc/mtab"), "overlay / overlay rw 0 0\n").unwrap();
+        link_mtab(&rootfs).unwrap();
+        assert!(rootfs.join("etc/mtab").is_file());
+
+        std::fs::remove_dir_all(&rootfs).unwrap();
+    }
+}
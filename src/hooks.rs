@@ -0,0 +1,44 @@
+use std::process::Command;
+
+use anyhow::Context;
+use nix::unistd::Pid;
+
+/// Runs a lifecycle hook on the host with `/bin/sh -c`. The hook gets the container's ID
+/// and the host PID of its init in `TOY_CONTAINER_ID` and `TOY_CONTAINER_PID`, and the
+/// lifecycle point in `TOY_HOOK`.
+pub(crate) fn run_hook(hook: &str, command: &str, id: &str, pid: Pid) -> anyhow::Result<()> {
+    let status = Command::new("/bin/sh")
+        .args(["-c", command])
+        .env("TOY_HOOK", hook)
+        .env("TOY_CONTAINER_ID", id)
+        .env("TOY_CONTAINER_PID", pid.to_string())
+        .status()
+        .with_context(|| format!("failed to run {} hook '{}'", hook, command))?;
+    if !status.success() {
+        anyhow::bail!("{} hook '{}' failed with {}", hook, command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_hook() {
+        let out = std::env::temp_dir().join(format!("toy_container_hook_{}", std::process::id()));
+        let command = format!(
+            "echo \"$TOY_HOOK $TOY_CONTAINER_ID $TOY_CONTAINER_PID\" > {}",
+            out.display()
+        );
+        run_hook("prestart", &command, "0123456789abcdef", Pid::from_raw(42)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "prestart 0123456789abcdef 42\n"
+        );
+        std::fs::remove_file(&out).unwrap();
+
+        let err = run_hook("poststop", "exit 3", "id", Pid::from_raw(42)).unwrap_err();
+        assert!(err.to_string().contains("poststop hook 'exit 3' failed"));
+    }
+}
@@ -0,0 +1,18 @@
+//! Toy container runtime, sandboxing a command with Linux namespaces, cgroups v2 and an
+//! overlay filesystem.
+//!
+//! [run] starts a command in a new container described by a [ContainerConfig] and returns
+//! its [RunOutcome]. The `container` binary is a command line front end to it.
+
+pub mod batch;
+pub mod cgroups;
+pub mod container;
+mod events;
+pub mod fs;
+mod hooks;
+mod limiter;
+pub mod net;
+mod retry;
+mod sampler;
+
+pub use container::{ContainerConfig, RunOutcome, run};
@@ -0,0 +1,89 @@
+use anyhow::Result;
+
+use crate::cgroups::{Limits, required_controllers};
+
+/// Applies resource limits to the container's processes. Implemented by
+/// [crate::cgroups::Cgroup] for cgroup v2, other backends can be plugged in the same way.
+pub trait Limiter {
+    /// Applies `limits` to the processes added with [Limiter::add_process]
+    fn apply(&self, limits: &Limits) -> Result<()>;
+
+    /// Puts a process under the limits
+    fn add_process(&self, pid: i32) -> Result<()>;
+}
+
+/// Limiter which doesn't limit anything, used when no cgroup can be created
+pub struct NoopLimiter;
+
+impl Limiter for NoopLimiter {
+    fn apply(&self, limits: &Limits) -> Result<()> {
+        if !required_controllers(limits).is_empty()
+            || limits.swappiness.is_some()
+            || limits.max_descendants.is_some()
+            || limits.max_depth.is_some()
+        {
+            eprintln!("warning: resource limits require root, ignoring them");
+        }
+        Ok(())
+    }
+
+    fn add_process(&self, _pid: i32) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Applies `limits` to the process `pid` with `limiter`
+pub fn limit_process(limiter: &dyn Limiter, limits: &Limits, pid: i32) -> Result<()> {
+    limiter.apply(limits)?;
+    limiter.add_process(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records the calls made to it
+    #[derive(Default)]
+    struct RecordingLimiter {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl Limiter for RecordingLimiter {
+        fn apply(&self, limits: &Limits) -> Result<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("apply mem={:?}", limits.mem));
+            Ok(())
+        }
+
+        fn add_process(&self, pid: i32) -> Result<()> {
+            self.calls.borrow_mut().push(format!("add {}", pid));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_limit_process_applies_before_adding() {
+        let limiter = RecordingLimiter::default();
+        let limits = Limits {
+            mem: Some(String::from("64M")),
+            ..Limits::default()
+        };
+        limit_process(&limiter, &limits, 42).unwrap();
+        assert_eq!(
+            *limiter.calls.borrow(),
+            ["apply mem=Some(\"64M\")", "add 42"]
+        );
+    }
+
+    #[test]
+    fn test_noop_limiter() {
+        let limits = Limits {
+            cpu: Some(String::from("0.5")),
+            ..Limits::default()
+        };
+        assert!(limit_process(&NoopLimiter, &limits, 42).is_ok());
+        assert!(limit_process(&NoopLimiter, &Limits::default(), 42).is_ok());
+    }
+}
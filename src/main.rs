@@ -1,49 +1,625 @@
-mod cgroups;
-mod container;
-mod fs;
-mod net;
-
-use std::process::ExitCode;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
 
+use caps::Capability;
 use clap::Parser;
 
-use container::run_in_container;
+use nix::{sched::CloneFlags, sys::signal::Signal};
+
+use ::container::{
+    ContainerConfig, batch,
+    cgroups::{self, Limits},
+    container::{
+        self, parse_capability, parse_isolate, parse_label, parse_signal, parse_sysctl,
+        parse_umask, read_hostname_file, validate_hostname,
+    },
+    fs::{self, Device, FsConfig, MountSpec},
+    net::{self, DnsConfig},
+    run,
+};
 
 /// A simple container runtime demonstrating Linux namespaces and cgroups
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Hostname for the container
-    #[arg(long)]
+    #[arg(long, value_parser = validate_hostname)]
     hostname: Option<String>,
 
+    /// Read the hostname for the container from the first line of a file
+    #[arg(long, conflicts_with = "hostname")]
+    hostname_file: Option<PathBuf>,
+
+    /// NIS domain name for the container
+    #[arg(long, value_parser = validate_hostname)]
+    domainname: Option<String>,
+
+    /// Name of the host bridge the container is attached to, at most 15 characters
+    #[arg(long, default_value = net::DEFAULT_BRIDGE, value_parser = net::parse_interface_name)]
+    bridge: String,
+
+    /// Join this existing network namespace, e.g. /run/netns/pod1, instead of creating one
+    /// with a bridge and veth. Containers started with the same namespace share the network.
+    /// Requires root
+    #[arg(long, conflicts_with = "gateway")]
+    netns: Option<PathBuf>,
+
+    /// Default gateway of the container, an address in its subnet. The bridge address by default
+    #[arg(long)]
+    gateway: Option<Ipv4Addr>,
+
+    /// Additional name resolving to the container's address via /etc/hosts. Can be repeated
+    #[arg(long = "network-alias", value_parser = validate_hostname)]
+    network_aliases: Vec<String>,
+
+    /// DNS server written to the container's /etc/resolv.conf. Can be repeated
+    #[arg(long = "dns")]
+    dns_servers: Vec<IpAddr>,
+
+    /// DNS search domain for short names in the container. Can be repeated
+    #[arg(long = "dns-search", value_parser = validate_hostname)]
+    dns_search: Vec<String>,
+
+    /// Resolver option for the container, e.g. ndots:2. Can be repeated
+    #[arg(long = "dns-option", value_parser = net::parse_dns_option)]
+    dns_options: Vec<String>,
+
+    /// Set a namespaced sysctl in the container, e.g. net.core.somaxconn=1024. Can be repeated
+    #[arg(long = "sysctl", value_parser = parse_sysctl)]
+    sysctls: Vec<(String, String)>,
+
     /// Drop all the capabilities for the command
     #[arg(long)]
     drop_caps: bool,
-    /// CPU shares for the container, e.g. 0.5, 1, etc
+
+    /// Capability to keep when dropping capabilities, e.g. NET_BIND_SERVICE. Can be repeated
+    #[arg(long, requires = "drop_caps", value_parser = parse_capability)]
+    cap_keep: Vec<Capability>,
+
+    /// INSECURE: keep all capabilities, expose the host's /dev and don't mask
+    /// sensitive /proc entries. Meant for debugging and nested container experiments
+    #[arg(long, conflicts_with = "drop_caps")]
+    privileged: bool,
+
+    /// Expose /dev/fuse in the container and keep CAP_SYS_ADMIN for mounting FUSE filesystems
+    #[arg(long)]
+    enable_fuse: bool,
+
+    /// CPU shares for the container, e.g. 0.5, 1, 50%, 150%, etc.
+    /// Defaults to the TOY_CPU environment variable unless --cpu-quota is given
     #[arg(short, long)]
     cpu: Option<String>,
 
-    /// Memory limit for the container in bytes or Mb/Gb, e.g. 128M, 1Gb, etc
+    /// Raw cpu.max quota in microseconds, alternative to --cpu
+    #[arg(long, conflicts_with = "cpu")]
+    cpu_quota: Option<u64>,
+
+    /// Raw cpu.max period in microseconds, 100000 by default
+    #[arg(long, requires = "cpu_quota")]
+    cpu_period: Option<u64>,
+
+    /// CPU weight for the container as a nice value, -20 (most CPU) to 19 (least CPU)
+    #[arg(long, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    cpu_nice: Option<i32>,
+
+    /// NUMA nodes the container may allocate memory from, e.g. 0 or 0-1,3
+    #[arg(long)]
+    cpuset_mems: Option<String>,
+
+    /// Memory limit for the container in bytes or Mb/Gb, e.g. 128M, 1Gb, etc.
+    /// Defaults to the TOY_MEM environment variable
     #[arg(short, long)]
     mem: Option<String>,
 
+    /// Memory the container is protected from reclaim up to, best-effort (memory.low), e.g. 64M
+    #[arg(long)]
+    memory_reservation: Option<String>,
+
+    /// Most cgroups the container may create below its own cgroup, e.g. 0 to allow none
+    #[arg(long)]
+    cgroup_max_descendants: Option<u32>,
+
+    /// How deep the cgroups created by the container may be nested below its own cgroup
+    #[arg(long)]
+    cgroup_max_depth: Option<u32>,
+
+    /// Most processes and threads the container may run at once (pids.max), e.g. 100
+    #[arg(long, value_parser = cgroups::parse_pids_limit)]
+    pids: Option<u64>,
+
+    /// How eagerly the container's memory is swapped out, 0 to 200. Only works on cgroup
+    /// hierarchies with memory.swappiness, cgroup v2 has none and fails with a hint
+    #[arg(long, value_parser = clap::value_parser!(u8).range(cgroups::SWAPPINESS_RANGE))]
+    memory_swappiness: Option<u8>,
+
+    /// Mount point of the cgroup v2 hierarchy
+    #[arg(long, default_value = cgroups::CGROUP_PATH)]
+    cgroup_root: PathBuf,
+
+    /// Create the container's cgroup below this cgroup, relative to --cgroup-root, e.g. batch/jobs
+    #[arg(long, value_parser = cgroups::parse_cgroup_parent)]
+    cgroup_parent: Option<PathBuf>,
+
+    /// Shell command run on the host once the container is set up, before its command starts.
+    /// Gets TOY_CONTAINER_ID and TOY_CONTAINER_PID in the environment. A failure aborts the run
+    #[arg(long)]
+    hook_prestart: Option<String>,
+
+    /// Shell command run on the host after the container exited and was torn down, with the
+    /// same environment as --hook-prestart. A failure only prints a warning
+    #[arg(long)]
+    hook_poststop: Option<String>,
+
+    /// When the command exits with a non-zero code or is killed, move the overlay work
+    /// directory and upper layer to fs/failed/ID for inspection instead of discarding them
+    #[arg(long, conflicts_with = "rootfs_size")]
+    preserve_on_failure: bool,
+
+    /// Keep the container's overlay directories, with everything it wrote, in
+    /// fs/interrupted/ID when it is stopped with Ctrl-C or SIGTERM
+    #[arg(long, conflicts_with_all = ["rootfs_size", "persist"])]
+    keep_upper_on_signal: bool,
+
+    /// Adjust how likely the kernel is to kill the container when the host runs out of memory,
+    /// -1000 (never) to 1000 (first). Negative values require root
+    #[arg(long, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-1000..=1000))]
+    oom_score_adj: Option<i32>,
+
+    /// Keep the container's cgroup after it exits, to inspect files like memory.events. It has
+    /// to be removed with rmdir afterwards
+    #[arg(long)]
+    no_cgroup_cleanup: bool,
+
+    /// Signal sent to the command when the container is asked to stop, e.g. SIGINT, QUIT or 3
+    #[arg(long, default_value = "SIGTERM", value_parser = parse_signal)]
+    stop_signal: Signal,
+
+    /// Seconds to wait for the container to exit after the stop signal before killing it
+    #[arg(long, default_value_t = 10)]
+    stop_timeout: u64,
+
+    /// Kill the container if it is still running after this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Bind-mount a host path into the container, HOST:CONTAINER[:OPTIONS], where OPTIONS is a
+    /// comma separated list of ro or rw and shared, slave or private propagation. Can be repeated
+    #[arg(short, long = "volume", value_parser = fs::parse_volume_mount)]
+    volumes: Vec<MountSpec>,
+
+    /// Mount an empty tmpfs in the container, CONTAINER[:SIZE]. Can be repeated
+    #[arg(long = "tmpfs", value_parser = fs::parse_tmpfs)]
+    tmpfs: Vec<MountSpec>,
+
+    /// Bind-mount this runtime's executable read-only at PATH in the container, to start
+    /// nested containers. The image has to provide its libraries, or the runtime has to be
+    /// built statically, e.g. with RUSTFLAGS="-C target-feature=+crt-static"
+    #[arg(long, value_name = "PATH", value_parser = fs::parse_self_mount)]
+    self_mount: Option<MountSpec>,
+
+    /// Bind-mount a static qemu-user binary read-only at the same path in the container, to
+    /// run a foreign-architecture rootfs. The host needs a binfmt_misc handler for the
+    /// architecture that uses this binary as its interpreter
+    #[arg(long, value_name = "PATH", value_parser = fs::parse_qemu)]
+    qemu: Option<MountSpec>,
+
+    /// Pass a host device into the container, HOST[:CONTAINER][:rwm]. Can be repeated
+    #[arg(long = "device", value_parser = fs::parse_device)]
+    devices: Vec<Device>,
+
+    /// Add a mount, e.g. type=bind,source=/data,target=/data,readonly or
+    /// type=tmpfs,target=/cache,tmpfs-size=64m. Bind mounts accept
+    /// bind-propagation=shared|slave|private. Can be repeated
+    #[arg(long = "mount", value_parser = fs::parse_mount)]
+    mounts: Vec<MountSpec>,
+
+    /// Hide a path inside the container, in addition to sensitive /proc entries. Can be repeated
+    #[arg(long = "masked-path", value_parser = fs::parse_container_path)]
+    masked_paths: Vec<PathBuf>,
+
+    /// Make a path inside the container read-only. Can be repeated
+    #[arg(long = "read-only-path", value_parser = fs::parse_container_path)]
+    read_only_paths: Vec<PathBuf>,
+
+    /// Disable address space layout randomization for the command, for reproducible debugging
+    #[arg(long)]
+    no_aslr: bool,
+
+    /// File mode creation mask for the command, in octal
+    #[arg(long, default_value = "022", value_parser = parse_umask)]
+    umask: libc::mode_t,
+
+    /// Confine the command with this AppArmor profile, if AppArmor is enabled
+    #[arg(long = "apparmor")]
+    apparmor_profile: Option<String>,
+
+    /// Keep a file descriptor open in the command, e.g. for socket activation. Can be repeated
+    #[arg(long = "preserve-fd", value_parser = clap::value_parser!(i32).range(3..))]
+    preserve_fds: Vec<i32>,
+
+    /// Append the command's stdout and stderr to this file instead of the terminal
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Unix socket to send start, exit and oom events to as JSON lines
+    #[arg(long)]
+    events_socket: Option<PathBuf>,
+
+    /// Write the generated container ID to this file, which must not exist yet
+    #[arg(long)]
+    cidfile: Option<PathBuf>,
+
+    /// Replace an existing --cidfile
+    #[arg(long, requires = "cidfile")]
+    force: bool,
+
+    /// Don't mount a fresh /proc, reuse the inherited one. Useful when running inside another
+    /// container. Note that /proc then shows processes of the parent PID namespace
+    #[arg(long)]
+    no_proc: bool,
+
+    /// Keep the container's writes (the overlay upper layer) between runs
+    #[arg(long)]
+    persist: bool,
+
+    /// Limit how much the container can write to its root filesystem, e.g. 512m or 2g.
+    /// The writes are kept in a loop-mounted ext4 image. Requires root
+    #[arg(long, conflicts_with = "persist", value_parser = fs::parse_size)]
+    rootfs_size: Option<u64>,
+
+    /// Size limit of tmpfs mounts which don't set their own size, e.g. 64m, 1g or 10%
+    #[arg(long, default_value = fs::DEFAULT_TMPFS_SIZE, value_parser = fs::validate_tmpfs_size)]
+    tmpfs_size: String,
+
+    /// Don't mount an empty tmpfs at /run
+    #[arg(long)]
+    no_run_tmpfs: bool,
+
+    /// Namespaces to create for the container, a comma separated list of pid, net, mount, uts,
+    /// ipc, user, cgroup and time. The rest is shared with the host, e.g. "uts" only sets the
+    /// hostname. All but time by default
+    #[arg(long, default_value = container::ALL_NAMESPACES, value_parser = parse_isolate)]
+    isolate: CloneFlags,
+
+    /// Shift the monotonic and boot time clocks seen in the container by this many seconds,
+    /// e.g. to test uptime handling. Creates a time namespace, which needs Linux 5.6
+    #[arg(long, value_name = "SECONDS", allow_hyphen_values = true)]
+    time_offset: Option<i64>,
+
+    /// Don't create a user namespace, root in the container is root on the host.
+    /// Requires running as root
+    #[arg(long)]
+    no_userns: bool,
+
+    /// Retry a container launch which failed with EAGAIN or EBUSY this many times, with a
+    /// growing delay of up to 5s. Failures inside the container once it started setting up,
+    /// e.g. mounting its filesystem, are not retried
+    #[arg(long, default_value_t = 0)]
+    max_startup_retries: u32,
+
+    /// Sample memory and CPU usage of the container every MS milliseconds. Memory usage is
+    /// only accounted with --mem or --memory-reservation, the column is empty otherwise
+    #[arg(long, value_name = "MS")]
+    sample_interval: Option<u64>,
+
+    /// Write resource usage samples as CSV to this file instead of stdout
+    #[arg(long, requires = "sample_interval")]
+    sample_output: Option<PathBuf>,
+
+    /// Warn when the container used no CPU for this many seconds while not all of its
+    /// processes were waiting for something, which hints at a hung container. Requires root
+    #[arg(long, visible_alias = "watchdog", value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    progress_watchdog: Option<u64>,
+
+    /// Attach key=value metadata to the container, shown in the JSON summary. Can be repeated
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Print a JSON summary of the run when the container exits
+    #[arg(long)]
+    json: bool,
+
+    /// When stopping the container, freeze its cgroup and deliver the stop signal to all of
+    /// its processes at once, whatever still runs after --stop-timeout is killed through
+    /// cgroup.kill. Requires root
+    #[arg(long)]
+    freeze_on_stop: bool,
+
+    /// Fail instead of warning when a memory limit exceeds the host's RAM
+    #[arg(long)]
+    strict: bool,
+
+    /// Print the options passed to the overlay mount, including the lower layer order
+    #[arg(long)]
+    print_mount_opts: bool,
+
+    /// Change to this host directory before doing anything else. The container filesystem is
+    /// then taken from its fs/ directory, and other relative paths are resolved against it too
+    #[arg(short = 'C', long, value_name = "HOST_DIR")]
+    chdir: Option<PathBuf>,
+
+    /// Print the formats accepted by the resource limit flags and exit
+    #[arg(long, exclusive = true)]
+    help_limits: bool,
+
+    /// Read commands from stdin, one per line with shell-like quoting, and run each of them
+    /// in a new container with the same settings, one after another
+    #[arg(long, conflicts_with_all = ["command", "cidfile"])]
+    batch: bool,
+
     /// Command to execute in the container
-    #[arg(required = true)]
-    command: String,
+    #[arg(required_unless_present_any = ["help_limits", "batch"])]
+    command: Option<String>,
 
     /// Arguments for the command
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     args: Vec<String>,
 }
 
+impl Args {
+    /// Fills in the CPU and memory limits not given on the command line from the TOY_CPU and
+    /// TOY_MEM variables looked up with `env`. They are only defaults, so unlike the flags
+    /// they don't conflict with --cpu-quota or --help-limits.
+    fn apply_limit_defaults(&mut self, env: impl Fn(&str) -> Option<String>) {
+        if self.cpu.is_none() && self.cpu_quota.is_none() {
+            self.cpu = env("TOY_CPU");
+        }
+        if self.mem.is_none() {
+            self.mem = env("TOY_MEM");
+        }
+    }
+}
+
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let mut args = Args::parse();
 
-    if let Err(e) = run_in_container(&args.command, &args.args, &args.cpu, &args.mem, &args.hostname, args.drop_caps) {
+    if args.help_limits {
+        print!("{}", cgroups::limits_help());
+        return ExitCode::SUCCESS;
+    }
+    args.apply_limit_defaults(|name| std::env::var(name).ok());
+
+    if let Some(dir) = &args.chdir
+        && let Err(e) = fs::enter_project_dir(dir)
+    {
         eprintln!("Error: {:#}", e);
         return ExitCode::FAILURE;
     }
 
+    let hostname = match &args.hostname_file {
+        Some(path) => match read_hostname_file(path) {
+            Ok(hostname) => Some(hostname),
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => args.hostname,
+    };
+
+    if let Some(qemu) = &args.qemu {
+        fs::check_binfmt_handler(qemu.target());
+    }
+
+    let mut devices = args.devices;
+    if args.enable_fuse {
+        devices.push(Device::new("/dev/fuse"));
+    }
+
+    let mut namespaces = args.isolate;
+    if args.no_userns {
+        namespaces.remove(CloneFlags::CLONE_NEWUSER);
+    }
+    if args.time_offset.is_some() {
+        namespaces |= container::CLONE_NEWTIME;
+    }
+
+    let config = ContainerConfig {
+        hostname,
+        domainname: args.domainname,
+        network_aliases: args.network_aliases,
+        dns: DnsConfig {
+            nameservers: args.dns_servers,
+            search: args.dns_search,
+            options: args.dns_options,
+        },
+        sysctls: args.sysctls,
+        drop_caps: args.drop_caps,
+        enable_fuse: args.enable_fuse,
+        cap_keep: args.cap_keep,
+        limits: Limits {
+            cpu: args.cpu,
+            mem: args.mem,
+            mem_reservation: args.memory_reservation,
+            cpu_quota: args.cpu_quota,
+            cpu_period: args.cpu_period,
+            cpu_nice: args.cpu_nice,
+            cpuset_mems: args.cpuset_mems,
+            pids: args.pids,
+            swappiness: args.memory_swappiness,
+            max_descendants: args.cgroup_max_descendants,
+            max_depth: args.cgroup_max_depth,
+        },
+        cgroup_root: args.cgroup_root,
+        cgroup_parent: args.cgroup_parent,
+        stop_signal: args.stop_signal,
+        stop_timeout: Duration::from_secs(args.stop_timeout),
+        bridge: args.bridge,
+        prestart_hook: args.hook_prestart,
+        poststop_hook: args.hook_poststop,
+        oom_score_adj: args.oom_score_adj,
+        preserve_on_failure: args.preserve_on_failure,
+        keep_upper_on_signal: args.keep_upper_on_signal,
+        keep_cgroup: args.no_cgroup_cleanup,
+        netns: args.netns,
+        gateway: args.gateway,
+        timeout: args.timeout.map(Duration::from_secs),
+        fs: FsConfig {
+            mounts: [
+                args.volumes,
+                args.tmpfs,
+                args.mounts,
+                args.self_mount.into_iter().collect(),
+                args.qemu.into_iter().collect(),
+            ]
+            .concat(),
+            masked_paths: args.masked_paths,
+            read_only_paths: args.read_only_paths,
+            no_proc: args.no_proc,
+            persist: args.persist,
+            no_run_tmpfs: args.no_run_tmpfs,
+            tmpfs_size: args.tmpfs_size,
+            privileged: args.privileged,
+            devices,
+            rootfs_size: args.rootfs_size,
+            print_mount_opts: args.print_mount_opts,
+        },
+        namespaces,
+        time_offset: args.time_offset,
+        freeze_on_stop: args.freeze_on_stop,
+        max_startup_retries: args.max_startup_retries,
+        sample_interval: args.sample_interval.map(Duration::from_millis),
+        sample_output: args.sample_output,
+        progress_watchdog: args.progress_watchdog.map(Duration::from_secs),
+        labels: args.labels,
+        no_aslr: args.no_aslr,
+        umask: args.umask,
+        apparmor_profile: args.apparmor_profile,
+        preserve_fds: args.preserve_fds,
+        log_file: args.log_file,
+        events_socket: args.events_socket,
+        cidfile: args.cidfile,
+        force_cidfile: args.force,
+    };
+
+    if let Err(e) = cgroups::check_host_memory(&config.limits, args.strict) {
+        eprintln!("Error: {:#}", e);
+        return ExitCode::FAILURE;
+    }
+
+    if args.batch {
+        return run_batch(&config, args.json);
+    }
+
+    let command = args.command.expect("clap requires a command");
+    match run(&command, &args.args, &config) {
+        Ok(outcome) => {
+            if args.json {
+                println!("{}", outcome.to_json());
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
     ExitCode::SUCCESS
 }
+
+/// Runs the commands read from stdin and reports the exit code of each. A stop request ends
+/// the batch after the container it interrupted. Fails if any of the containers could not be
+/// run or the batch was stopped.
+fn run_batch(config: &ContainerConfig, json: bool) -> ExitCode {
+    // stdin is read completely first, so the commands can't consume the rest of the batch
+    let commands = match batch::read_commands(std::io::stdin().lock()) {
+        Ok(commands) => commands,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let results = batch::run_commands(
+        &commands,
+        |command, args| run(command, args, config),
+        |outcome| outcome.interrupted,
+    );
+    let results_len = results.len();
+    let mut failed = results_len < commands.len();
+    for (words, result) in commands.iter().zip(results) {
+        match result {
+            Ok(outcome) if json => println!("{}", outcome.to_json()),
+            Ok(outcome) => println!("{}: exit code {}", words.join(" "), outcome.exit_code),
+            Err(e) => {
+                eprintln!("{}: Error: {:#}", words.join(" "), e);
+                failed = true;
+            }
+        }
+    }
+    for words in &commands[results_len..] {
+        eprintln!("{}: not run, the batch was stopped", words.join(" "));
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_from_env() {
+        let env = |name: &str| match name {
+            "TOY_CPU" => Some(String::from("0.5")),
+            "TOY_MEM" => Some(String::from("64M")),
+            _ => None,
+        };
+
+        let mut args = Args::try_parse_from(["container", "/bin/true"]).unwrap();
+        args.apply_limit_defaults(env);
+        assert_eq!(args.cpu.as_deref(), Some("0.5"));
+        assert_eq!(args.mem.as_deref(), Some("64M"));
+
+        let mut args =
+            Args::try_parse_from(["container", "--cpu", "2", "--mem", "128M", "/bin/true"])
+                .unwrap();
+        args.apply_limit_defaults(env);
+        assert_eq!(args.cpu.as_deref(), Some("2"));
+        assert_eq!(args.mem.as_deref(), Some("128M"));
+
+        // the defaults don't conflict with the raw quota
+        let mut args = Args::try_parse_from([
+            "container",
+            "--cpu-quota",
+            "30000",
+            "--cpu-period",
+            "60000",
+            "/bin/true",
+        ])
+        .unwrap();
+        args.apply_limit_defaults(env);
+        assert_eq!(args.cpu, None);
+        assert_eq!(args.cpu_quota, Some(30000));
+        assert_eq!(args.mem.as_deref(), Some("64M"));
+
+        // nor with --help-limits, which takes no other flags
+        let args = Args::try_parse_from(["container", "--help-limits"]).unwrap();
+        assert!(args.help_limits);
+    }
+
+    #[test]
+    fn test_watchdog_alias() {
+        for flag in ["--progress-watchdog", "--watchdog"] {
+            let args = Args::try_parse_from(["container", flag, "30", "/bin/true"]).unwrap();
+            assert_eq!(args.progress_watchdog, Some(30));
+        }
+    }
+
+    #[test]
+    fn test_memory_swappiness_range() {
+        let args =
+            Args::try_parse_from(["container", "--memory-swappiness", "200", "/bin/true"]).unwrap();
+        assert_eq!(args.memory_swappiness, Some(200));
+        assert!(
+            Args::try_parse_from(["container", "--memory-swappiness", "201", "/bin/true"]).is_err()
+        );
+    }
+}
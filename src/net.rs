@@ -1,23 +1,98 @@
-use std::{net::Ipv4Addr, process::Command};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
 
 use anyhow::Context;
-use nix::unistd::Pid;
+use nix::{
+    sched::{CloneFlags, setns},
+    unistd::Pid,
+};
+
+use crate::retry::retry;
 
 use cidr::Ipv4Cidr;
 
-const BRIDGE_NAME: &str = "br0";
+/// Bridge the containers are attached to unless `--bridge` is given
+pub const DEFAULT_BRIDGE: &str = "toy0";
+/// Resolver configuration written to the container's /etc/resolv.conf
+#[derive(Debug, Default)]
+pub struct DnsConfig {
+    pub nameservers: Vec<IpAddr>,
+    /// Domains appended to short names when resolving
+    pub search: Vec<String>,
+    /// Resolver options, e.g. ndots:2 or timeout:1
+    pub options: Vec<String>,
+}
+
+impl DnsConfig {
+    fn is_empty(&self) -> bool {
+        self.nameservers.is_empty() && self.search.is_empty() && self.options.is_empty()
+    }
+}
+
+/// Longest network interface name the kernel accepts, IFNAMSIZ without the terminating NUL
+const MAX_INTERFACE_NAME: usize = 15;
 const VETH_HOST: &str = "veth0h0";
 const VETH_CONTAINER: &str = "veth0c0";
 
-/// executes ip command with arguments
+/// number of attempts to move the veth into the container namespace
+const MOVE_ATTEMPTS: u32 = 5;
+/// delay before the first retry, doubled after every failed attempt
+const MOVE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// executes ip command with arguments, failing if it exits with non-zero status
 fn ip(args: &[&str]) -> anyhow::Result<()> {
-    Command::new("/sbin/ip")
+    let status = Command::new("/sbin/ip")
         .args(args)
         .status()
         .context(format!("Failed to execute ip {:?}", args))?;
+    if !status.success() {
+        anyhow::bail!("ip {:?} failed with {}", args, status);
+    }
     Ok(())
 }
 
+/// validates a resolver option for `--dns-option`, e.g. ndots:2
+pub fn parse_dns_option(option: &str) -> anyhow::Result<String> {
+    if option.is_empty() || option.contains(char::is_whitespace) {
+        anyhow::bail!("invalid DNS option '{}'", option);
+    }
+    Ok(option.to_string())
+}
+
+/// validates a network interface name, as used for `--bridge`
+pub fn parse_interface_name(name: &str) -> anyhow::Result<String> {
+    if name.is_empty() || name.len() > MAX_INTERFACE_NAME {
+        anyhow::bail!(
+            "interface name '{}' must be 1 to {} characters long",
+            name,
+            MAX_INTERFACE_NAME
+        );
+    }
+    if name == "."
+        || name == ".."
+        || name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+    {
+        anyhow::bail!("invalid interface name '{}'", name);
+    }
+    Ok(name.to_string())
+}
+
+/// checks whether a network device exists in the current network namespace
+fn link_exists(name: &str) -> bool {
+    Command::new("/sbin/ip")
+        .args(["link", "show", "dev", name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
 fn ips_from_cidr(netw: &Ipv4Cidr) -> anyhow::Result<(Ipv4Addr, Ipv4Addr)> {
     let mut cidr_iter = netw.iter();
     let host_ip = cidr_iter
@@ -31,23 +106,45 @@ fn ips_from_cidr(netw: &Ipv4Cidr) -> anyhow::Result<(Ipv4Addr, Ipv4Addr)> {
     Ok((host_ip, container_ip))
 }
 
-/// creates a bridge with the given IP address and brings the interface up
-fn create_bridge(ipaddr: &Ipv4Addr) -> anyhow::Result<()> {
-    ip(&["link", "add", "name", BRIDGE_NAME, "type", "bridge"]).context("creating bridge")?;
+/// returns the address assigned to the container side of the veth pair
+pub(crate) fn container_ip(netw: &Ipv4Cidr) -> anyhow::Result<Ipv4Addr> {
+    let (_, container_ip) = ips_from_cidr(netw)?;
+    Ok(container_ip)
+}
+
+/// checks that a `--gateway` address can be reached directly from the container, i.e. is a
+/// host address in the container's subnet other than the container's own
+pub(crate) fn validate_gateway(netw: &Ipv4Cidr, gateway: &Ipv4Addr) -> anyhow::Result<()> {
+    let container_ip = container_ip(netw)?;
+    if !netw.contains(gateway)
+        || *gateway == netw.first_address()
+        || *gateway == netw.last_address()
+    {
+        anyhow::bail!("gateway {} is not a host address in {}", gateway, netw);
+    }
+    if *gateway == container_ip {
+        anyhow::bail!("gateway {} is the container's own address", gateway);
+    }
+    Ok(())
+}
+
+/// creates a bridge with the given name and IP address and brings the interface up
+fn create_bridge(bridge: &str, ipaddr: &Ipv4Addr) -> anyhow::Result<()> {
+    ip(&["link", "add", "name", bridge, "type", "bridge"]).context("creating bridge")?;
     ip(&[
         "addr",
         "add",
         format!("{}/24", ipaddr).as_str(),
         "dev",
-        BRIDGE_NAME,
+        bridge,
     ])
     .context("adding IP address to bridge")?;
-    ip(&["link", "set", "dev", BRIDGE_NAME, "up"]).context("bringing up bridge")?;
+    ip(&["link", "set", "dev", bridge, "up"]).context("bringing up bridge")?;
     Ok(())
 }
 
-/// creates a veth pair with the given container IP address and brings the interface up
-fn create_veth_pair() -> anyhow::Result<()> {
+/// creates a veth pair, brings the host side up and attaches it to `bridge`
+fn create_veth_pair(bridge: &str) -> anyhow::Result<()> {
     // create veth pair
     ip(&[
         "link",
@@ -66,46 +163,64 @@ fn create_veth_pair() -> anyhow::Result<()> {
     ip(&["link", "set", "dev", VETH_HOST, "up"]).context("bringing up host side")?;
 
     // attach host veth side to the bridge interface
-    ip(&["link", "set", "dev", VETH_HOST, "master", BRIDGE_NAME])
+    ip(&["link", "set", "dev", VETH_HOST, "master", bridge])
         .context("attaching host side to the bridge")?;
 
     Ok(())
 }
 
 pub(crate) fn move_into_container(child_pid: Pid) -> anyhow::Result<()> {
-    // move child side to child namespace
+    // move child side to child namespace, the namespace may not be ready right after clone
     let pid_s: String = child_pid.to_string();
-    ip(&[
-        "link",
-        "set",
-        "dev",
-        VETH_CONTAINER,
-        "netns",
-        pid_s.as_str(),
-    ])
+    retry_move(|| {
+        ip(&[
+            "link",
+            "set",
+            "dev",
+            VETH_CONTAINER,
+            "netns",
+            pid_s.as_str(),
+        ])
+    })
     .context("moving veth0c0 to child namespace")?;
 
+    // the device must be gone from the host namespace now
+    if link_exists(VETH_CONTAINER) {
+        anyhow::bail!("{} is still present in the host namespace", VETH_CONTAINER);
+    }
+
     Ok(())
 }
 
+/// Retries moving the veth with `op` on any failure. A failing `ip` only reports its exit
+/// status, there is no errno telling a transient failure from a permanent one.
+fn retry_move(op: impl FnMut() -> anyhow::Result<()>) -> anyhow::Result<()> {
+    retry(MOVE_ATTEMPTS, MOVE_RETRY_DELAY, |_| true, op)
+}
+
 /// setup the network on the host side:
-/// - create bridge and assign first address in the CIDR to the bridge interface
+/// - create `bridge` and assign first address in the CIDR to the bridge interface
 /// - attach host veth side to the bridge interface
 /// - assign IP address to container veth side
 /// - move container veth side into container namespace
-pub(crate) fn setup_network_host(netw: &Ipv4Cidr) -> anyhow::Result<()> {
+pub(crate) fn setup_network_host(bridge: &str, netw: &Ipv4Cidr) -> anyhow::Result<()> {
     let (host_ip, _) = ips_from_cidr(netw)?;
 
-    create_bridge(&host_ip)?;
-    create_veth_pair()?;
+    create_bridge(bridge, &host_ip)?;
+    create_veth_pair(bridge)?;
 
     Ok(())
 }
 
 /// bring up the network on the container side:
 /// - bring up the container veth side, if the `veth` parameter is true
+/// - route through `gateway`, or the bridge address if it is not set
 /// - bring up the loopback interface
-pub(crate) fn bring_up_container_net(netw: &Ipv4Cidr, is_root: bool) -> anyhow::Result<()> {
+pub(crate) fn bring_up_container_net(
+    netw: &Ipv4Cidr,
+    gateway: Option<Ipv4Addr>,
+    is_root: bool,
+) -> anyhow::Result<()> {
     let (host_ip, container_ip) = ips_from_cidr(netw)?;
 
     if is_root {
@@ -129,7 +244,7 @@ pub(crate) fn bring_up_container_net(netw: &Ipv4Cidr, is_root: bool) -> anyhow::
             "add",
             "default",
             "via",
-            host_ip.to_string().as_str(),
+            gateway.unwrap_or(host_ip).to_string().as_str(),
             "dev",
             VETH_CONTAINER,
         ])
@@ -140,8 +255,215 @@ pub(crate) fn bring_up_container_net(netw: &Ipv4Cidr, is_root: bool) -> anyhow::
     Ok(())
 }
 
-pub(crate) fn cleanup_network() -> anyhow::Result<()> {
-    ip(&["link", "delete", BRIDGE_NAME]).context("removing bridge device")?;
+/// runs `f` with the calling thread in the network namespace at `path`, e.g. one created by
+/// `ip netns add` in /run/netns, and switches back afterwards. Processes cloned by `f`
+/// stay in the joined namespace.
+pub(crate) fn in_netns<T>(path: &Path, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let original = File::open("/proc/thread-self/ns/net").context("opening current netns")?;
+    let target = File::open(path).with_context(|| format!("opening netns {:?}", path))?;
+    setns(&target, CloneFlags::CLONE_NEWNET)
+        .with_context(|| format!("joining netns {:?}", path))?;
+    let result = f();
+    setns(&original, CloneFlags::CLONE_NEWNET).context("returning to the original netns")?;
+    result
+}
+
+/// formats an /etc/hosts line resolving `names` to `ip`
+fn hosts_entry(ip: &Ipv4Addr, names: &[&str]) -> String {
+    format!("{}\t{}\n", ip, names.join(" "))
+}
 
+/// appends an entry resolving the hostname and aliases to the container address to /etc/hosts.
+/// Without a veth the names resolve to the loopback address.
+pub(crate) fn add_hosts_entry(
+    netw: &Ipv4Cidr,
+    has_veth: bool,
+    hostname: &Option<String>,
+    aliases: &[String],
+) -> anyhow::Result<()> {
+    let names: Vec<&str> = hostname.iter().chain(aliases).map(String::as_str).collect();
+    if names.is_empty() {
+        return Ok(());
+    }
+    let ip = if has_veth {
+        container_ip(netw)?
+    } else {
+        Ipv4Addr::LOCALHOST
+    };
+    let mut hosts = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open("/etc/hosts")
+        .context("opening /etc/hosts")?;
+    hosts
+        .write_all(hosts_entry(&ip, &names).as_bytes())
+        .context("writing /etc/hosts")?;
     Ok(())
 }
+
+/// formats resolv.conf for `dns`. The nameservers of the `existing` file are kept when
+/// `dns` has none
+fn resolv_conf(dns: &DnsConfig, existing: &str) -> String {
+    let mut conf = String::new();
+    if dns.nameservers.is_empty() {
+        for line in existing.lines() {
+            if line.split_whitespace().next() == Some("nameserver") {
+                conf.push_str(line);
+                conf.push('\n');
+            }
+        }
+    }
+    for nameserver in &dns.nameservers {
+        conf.push_str(&format!("nameserver {}\n", nameserver));
+    }
+    if !dns.search.is_empty() {
+        conf.push_str(&format!("search {}\n", dns.search.join(" ")));
+    }
+    if !dns.options.is_empty() {
+        conf.push_str(&format!("options {}\n", dns.options.join(" ")));
+    }
+    conf
+}
+
+/// replaces /etc/resolv.conf with the one generated for `dns`, if any DNS settings are given
+pub(crate) fn write_resolv_conf(dns: &DnsConfig) -> anyhow::Result<()> {
+    if dns.is_empty() {
+        return Ok(());
+    }
+    let existing = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    std::fs::write("/etc/resolv.conf", resolv_conf(dns, &existing))
+        .context("writing /etc/resolv.conf")?;
+    Ok(())
+}
+
+/// lists the devices the host side of the network consists of which still exist, in the
+/// order they are removed. Deleting either end of the veth pair removes both, the container
+/// end is only left on the host if the setup failed before moving it.
+fn leftover_links(bridge: &str, exists: impl Fn(&str) -> bool) -> Vec<&str> {
+    let veth = [VETH_HOST, VETH_CONTAINER]
+        .into_iter()
+        .find(|name| exists(name));
+    veth.into_iter()
+        .chain(Some(bridge).filter(|bridge| exists(bridge)))
+        .collect()
+}
+
+/// removes the veth pair and the bridge from the host, skipping devices which don't exist.
+/// Tries to remove every device and reports the first failure.
+pub(crate) fn cleanup_network(bridge: &str) -> anyhow::Result<()> {
+    let mut result = Ok(());
+    for link in leftover_links(bridge, link_exists) {
+        if let Err(e) = ip(&["link", "delete", link]) {
+            result = result.and(Err(e.context(format!("removing {}", link))));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts_entry() {
+        assert_eq!(
+            hosts_entry(&Ipv4Addr::new(192, 168, 200, 2), &["box", "db", "cache"]),
+            "192.168.200.2\tbox db cache\n"
+        );
+    }
+
+    #[test]
+    fn test_resolv_conf() {
+        let dns = DnsConfig {
+            nameservers: vec![
+                IpAddr::from([1, 1, 1, 1]),
+                "2606:4700::1111".parse().unwrap(),
+            ],
+            search: vec![String::from("svc.local"), String::from("example.com")],
+            options: vec![String::from("ndots:2"), String::from("timeout:1")],
+        };
+        assert_eq!(
+            resolv_conf(&dns, "nameserver 8.8.8.8\n"),
+            "nameserver 1.1.1.1\nnameserver 2606:4700::1111\nsearch svc.local example.com\noptions ndots:2 timeout:1\n"
+        );
+
+        let search_only = DnsConfig {
+            search: vec![String::from("svc.local")],
+            ..Default::default()
+        };
+        assert_eq!(
+            resolv_conf(
+                &search_only,
+                "# generated\nnameserver 8.8.8.8\nsearch old\n"
+            ),
+            "nameserver 8.8.8.8\nsearch svc.local\n"
+        );
+        assert!(parse_dns_option("ndots:2").is_ok());
+        assert!(parse_dns_option("ndots: 2").is_err());
+    }
+
+    #[test]
+    fn test_in_netns() {
+        let own = std::fs::read_link("/proc/thread-self/ns/net").unwrap();
+        let inside = in_netns(Path::new("/proc/self/ns/net"), || {
+            Ok(std::fs::read_link("/proc/thread-self/ns/net")?)
+        });
+        // joining a namespace needs CAP_SYS_ADMIN
+        if let Ok(inside) = inside {
+            assert_eq!(inside, own);
+        }
+        assert!(in_netns(Path::new("/nonexistent/netns"), || Ok(())).is_err());
+        assert_eq!(std::fs::read_link("/proc/thread-self/ns/net").unwrap(), own);
+    }
+
+    #[test]
+    fn test_leftover_links() {
+        // veth moved into the container, which removed it on exit
+        assert_eq!(leftover_links("toy0", |name| name == "toy0"), vec!["toy0"]);
+        // aborted before the container side was moved
+        assert_eq!(leftover_links("toy0", |_| true), vec![VETH_HOST, "toy0"]);
+        assert_eq!(
+            leftover_links("toy0", |name| name == VETH_CONTAINER),
+            vec![VETH_CONTAINER]
+        );
+        assert!(leftover_links("toy0", |_| false).is_empty());
+    }
+
+    #[test]
+    fn test_retry_move_on_ip_failure() {
+        let mut calls = 0;
+        retry_move(|| {
+            calls += 1;
+            if calls < 3 {
+                anyhow::bail!("ip [\"link\", \"set\"] failed with exit status: 2");
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_validate_gateway() {
+        let netw = Ipv4Cidr::new(Ipv4Addr::new(192, 168, 200, 0), 24).unwrap();
+        assert!(validate_gateway(&netw, &Ipv4Addr::new(192, 168, 200, 1)).is_ok());
+        assert!(validate_gateway(&netw, &Ipv4Addr::new(192, 168, 200, 254)).is_ok());
+        assert!(validate_gateway(&netw, &Ipv4Addr::new(192, 168, 200, 2)).is_err());
+        assert!(validate_gateway(&netw, &Ipv4Addr::new(192, 168, 200, 0)).is_err());
+        assert!(validate_gateway(&netw, &Ipv4Addr::new(192, 168, 200, 255)).is_err());
+        assert!(validate_gateway(&netw, &Ipv4Addr::new(10, 0, 0, 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_interface_name() {
+        assert_eq!(parse_interface_name("toy0").unwrap(), "toy0");
+        assert_eq!(
+            parse_interface_name("toy-bridge-0123").unwrap(),
+            "toy-bridge-0123"
+        );
+        assert!(parse_interface_name("toy-bridge-01234").is_err());
+        assert!(parse_interface_name("").is_err());
+        assert!(parse_interface_name("toy 0").is_err());
+        assert!(parse_interface_name("toy/0").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::{thread, time::Duration};
+
+use nix::errno::Errno;
+
+/// Longest delay between two attempts, however many attempts were made
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Calls `op` until it succeeds, up to `attempts` times, doubling the delay between attempts
+/// up to [MAX_DELAY]. Errors for which `should_retry` is false are returned right away, e.g.
+/// [is_transient] only retries errors which may go away on their own.
+pub(crate) fn retry<T>(
+    attempts: u32,
+    initial_delay: Duration,
+    should_retry: impl Fn(&anyhow::Error) -> bool,
+    mut op: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut delay = initial_delay.min(MAX_DELAY);
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt >= attempts && attempts > 1 => {
+                return Err(err.context(format!("giving up after {} attempts", attempts)));
+            }
+            Err(err) if attempt >= attempts || !should_retry(&err) => return Err(err),
+            Err(_) => {
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Whether `err` was caused by EAGAIN or EBUSY, which may go away on their own, e.g. when
+/// another container exits. Anything else, like a missing rootfs or EPERM, fails the same way
+/// on every attempt.
+pub(crate) fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let errno = match cause.downcast_ref::<std::io::Error>() {
+            Some(io_error) => io_error.raw_os_error().map(Errno::from_raw),
+            None => cause.downcast_ref::<Errno>().copied(),
+        };
+        matches!(errno, Some(Errno::EAGAIN | Errno::EBUSY))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_retry_recovers_from_transient_failure() {
+        let mut calls = 0;
+        let result = retry(3, Duration::from_millis(1), is_transient, || {
+            calls += 1;
+            if calls < 3 {
+                return Err(Errno::EBUSY).context("failed to mount");
+            }
+            Ok(calls)
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = retry(3, Duration::from_millis(1), is_transient, || {
+            calls += 1;
+            Err(Errno::EAGAIN.into())
+        });
+        assert!(format!("{:#}", result.unwrap_err()).starts_with("giving up after 3 attempts"));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_single_attempt() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = retry(1, Duration::from_millis(1), is_transient, || {
+            calls += 1;
+            Err(Errno::EAGAIN.into())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "EAGAIN: Try again");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_retry_fails_fast_on_permanent_error() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = retry(3, Duration::from_millis(1), is_transient, || {
+            calls += 1;
+            Err(Errno::EPERM).context("failed to create cgroup")
+        });
+        assert_eq!(result.unwrap_err().to_string(), "failed to create cgroup");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&Errno::EAGAIN.into()));
+        assert!(is_transient(
+            &anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EBUSY)).context("rmdir")
+        ));
+        assert!(!is_transient(&Errno::ENOENT.into()));
+        assert!(!is_transient(&anyhow::anyhow!("rootfs not found")));
+    }
+}
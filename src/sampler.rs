@@ -0,0 +1,290 @@
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use nix::unistd::Pid;
+
+use crate::cgroups::Cgroup;
+
+/// Periodically samples the cgroup's memory and CPU usage and writes them to `output`
+/// as CSV lines `elapsed_ms,memory_bytes,cpu_usage_usec` until `stop` is set. `memory_bytes`
+/// is left empty if the cgroup's memory usage isn't accounted, see [Cgroup::memory_usage].
+pub(crate) fn sample(
+    cgroup: &Cgroup,
+    interval: Duration,
+    output: &mut dyn Write,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    writeln!(output, "elapsed_ms,memory_bytes,cpu_usage_usec")?;
+    while !stop.load(Ordering::Relaxed) {
+        let memory = cgroup
+            .memory_usage()?
+            .map_or(String::new(), |memory| memory.to_string());
+        let cpu = cgroup.cpu_usage_usec()?;
+        writeln!(output, "{},{},{}", start.elapsed().as_millis(), memory, cpu)
+            .context("failed to write sample")?;
+        output.flush()?;
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// Samples the cgroup's CPU and memory usage until `stop` is set and warns when the container
+/// made no progress for `stall_after`, see [ProgressCheck]. Only CPU usage is tracked if the
+/// cgroup's memory usage isn't accounted.
+pub(crate) fn watch_progress(
+    cgroup: &Cgroup,
+    stall_after: Duration,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    let interval = (stall_after / 4).min(Duration::from_secs(1));
+    let mut check = ProgressCheck::new(stall_after);
+    while !stop.load(Ordering::Relaxed) {
+        let usage = (cgroup.cpu_usage_usec()?, cgroup.memory_usage()?);
+        let idle = voluntarily_sleeping(&cgroup.procs()?);
+        if check.update(usage, idle, Instant::now()) {
+            eprintln!(
+                "warning: the container used no CPU for {:?} without waiting for anything, \
+                 it may be hung",
+                stall_after
+            );
+        }
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// Heuristic for a hung container: its CPU and memory usage didn't change for `stall_after`
+/// although not all of its processes are sleeping voluntarily, e.g. waiting for input. A
+/// stopped process or one stuck in uninterruptible sleep looks like that.
+pub(crate) struct ProgressCheck {
+    stall_after: Duration,
+    /// CPU time in microseconds and memory in bytes, if accounted, at the last sample
+    last_usage: Option<(u64, Option<u64>)>,
+    /// Start of the current stall, if the container is stalled
+    stalled_since: Option<Instant>,
+    /// The current stall has been reported already
+    reported: bool,
+}
+
+impl ProgressCheck {
+    pub(crate) fn new(stall_after: Duration) -> Self {
+        ProgressCheck {
+            stall_after,
+            last_usage: None,
+            stalled_since: None,
+            reported: false,
+        }
+    }
+
+    /// Records a sample of the CPU and memory `usage` taken at `now`, and whether the
+    /// container's processes were all sleeping voluntarily.
+    ///
+    /// # Returns
+    /// True once per stall, when it has lasted `stall_after`
+    pub(crate) fn update(&mut self, usage: (u64, Option<u64>), idle: bool, now: Instant) -> bool {
+        let progressed = self.last_usage != Some(usage);
+        self.last_usage = Some(usage);
+        if progressed || idle {
+            self.stalled_since = None;
+            self.reported = false;
+            return false;
+        }
+        let since = *self.stalled_since.get_or_insert(now);
+        if self.reported || now - since < self.stall_after {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+}
+
+/// Whether every process in `pids` is in interruptible sleep, waiting for an event, or a
+/// zombie. Processes which can't be read have exited and are skipped.
+fn voluntarily_sleeping(pids: &[Pid]) -> bool {
+    pids.iter().all(|pid| {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .map_or(true, |stat| matches!(process_state(&stat), Some('S' | 'Z')))
+    })
+}
+
+/// Extracts the state letter from the content of `/proc/PID/stat`. The command name before
+/// it is in parentheses and may contain spaces and parentheses itself.
+fn process_state(stat: &str) -> Option<char> {
+    let (_, after_comm) = stat.rsplit_once(')')?;
+    after_comm.trim_start().chars().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgroups::Limits;
+    use nix::{
+        sys::{
+            signal::{Signal, kill},
+            wait::waitpid,
+        },
+        unistd::{ForkResult, fork},
+    };
+
+    #[test]
+    fn test_process_state() {
+        assert_eq!(process_state("42 (sh) S 1 42 42 0"), Some('S'));
+        assert_eq!(process_state("42 (my (odd) cmd) T 1 42"), Some('T'));
+        assert_eq!(process_state("42 (sh"), None);
+    }
+
+    #[test]
+    fn test_progress_check() {
+        let stall_after = Duration::from_secs(10);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // a busy loop keeps using CPU
+        let mut busy = ProgressCheck::new(stall_after);
+        for (secs, cpu) in [(0, 100), (10, 200), (20, 300), (30, 400)] {
+            assert!(!busy.update((cpu, Some(4096)), false, at(secs)));
+        }
+
+        // a process waiting for input uses no CPU, but sleeps voluntarily
+        let mut waiting = ProgressCheck::new(stall_after);
+        for secs in [0, 10, 20, 30] {
+            assert!(!waiting.update((100, Some(4096)), true, at(secs)));
+        }
+
+        // a stopped process neither uses CPU nor sleeps, reported once per stall
+        let mut stopped = ProgressCheck::new(stall_after);
+        assert!(!stopped.update((100, Some(4096)), false, at(0)));
+        assert!(!stopped.update((100, Some(4096)), false, at(5)));
+        assert!(stopped.update((100, Some(4096)), false, at(15)));
+        assert!(!stopped.update((100, Some(4096)), false, at(30)));
+        assert!(!stopped.update((200, Some(4096)), false, at(31)));
+        assert!(!stopped.update((200, Some(4096)), false, at(32)));
+        assert!(stopped.update((200, Some(4096)), false, at(42)));
+
+        // without memory accounting only CPU usage counts as progress
+        let mut cpu_only = ProgressCheck::new(stall_after);
+        assert!(!cpu_only.update((100, None), false, at(0)));
+        assert!(!cpu_only.update((100, None), false, at(5)));
+        assert!(cpu_only.update((100, None), false, at(15)));
+        assert!(!cpu_only.update((200, None), false, at(16)));
+    }
+
+    #[test]
+    fn test_sample_without_memory_controller() {
+        let root =
+            std::env::temp_dir().join(format!("toy_container_sampler_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        // no limits, so the fake cgroup has cpu.stat but no memory.current
+        let cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        std::fs::write(
+            root.join("toy_container/leaf/cpu.stat"),
+            "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\n",
+        )
+        .unwrap();
+
+        let stop = AtomicBool::new(false);
+        let mut output = Vec::new();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                stop.store(true, Ordering::Relaxed);
+            });
+            sample(&cgroup, Duration::from_millis(10), &mut output, &stop).unwrap();
+        });
+        drop(cgroup);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("elapsed_ms,memory_bytes,cpu_usage_usec"));
+        let samples: Vec<&str> = lines.collect();
+        assert!(!samples.is_empty());
+        for line in samples {
+            let columns: Vec<&str> = line.split(',').collect();
+            assert_eq!(columns[1..], ["", "1500"]);
+        }
+    }
+
+    /// Sample output which advances the fake cgroup's CPU usage by 1ms after every sample
+    struct AdvancingCpu<'a> {
+        cpu_stat: &'a std::path::Path,
+        usage_usec: u64,
+        output: Vec<u8>,
+    }
+
+    impl Write for AdvancingCpu<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.usage_usec += 1000;
+            std::fs::write(self.cpu_stat, format!("usage_usec {}\n", self.usage_usec))
+        }
+    }
+
+    #[test]
+    fn test_sample_rising_cpu_usage() {
+        let root = std::env::temp_dir().join(format!(
+            "toy_container_sampler_rising_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        let cgroup = Cgroup::new(&root, None, &Limits::default()).unwrap();
+        let cpu_stat = root.join("toy_container/leaf/cpu.stat");
+        std::fs::write(&cpu_stat, "usage_usec 0\n").unwrap();
+
+        let stop = AtomicBool::new(false);
+        let mut output = AdvancingCpu {
+            cpu_stat: &cpu_stat,
+            usage_usec: 0,
+            output: Vec::new(),
+        };
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                stop.store(true, Ordering::Relaxed);
+            });
+            sample(&cgroup, Duration::from_millis(10), &mut output, &stop).unwrap();
+        });
+        drop(cgroup);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let output = String::from_utf8(output.output).unwrap();
+        let cpu: Vec<u64> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.rsplit(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert!(cpu.len() >= 2);
+        let expected: Vec<u64> = (0..cpu.len() as u64).map(|n| n * 1000).collect();
+        assert_eq!(cpu, expected);
+    }
+
+    #[test]
+    fn test_stopped_process_is_not_sleeping() {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => loop {
+                unsafe { libc::pause() };
+            },
+            ForkResult::Parent { child } => {
+                thread::sleep(Duration::from_millis(50));
+                let paused = voluntarily_sleeping(&[child]);
+                kill(child, Signal::SIGSTOP).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                let stopped = voluntarily_sleeping(&[child]);
+                kill(child, Signal::SIGKILL).unwrap();
+                waitpid(child, None).unwrap();
+
+                assert!(paused);
+                assert!(!stopped);
+            }
+        }
+    }
+}